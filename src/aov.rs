@@ -0,0 +1,359 @@
+use cgmath::{ Vector3, InnerSpace };
+
+use crate::framebuffer::{ self, Framebuffer };
+use crate::hitable::{ Geometry, HitRecord };
+use crate::tonemap::ToneMap;
+
+// Auxiliary passes rendered alongside the beauty image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    Object,
+    Material
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::Object, Aov::Material];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Object => "object",
+            Aov::Material => "material"
+        }
+    }
+
+    // Indices must come out exactly, which half floats only manage up to 2048.
+    pub fn needs_full_precision(self) -> bool {
+        matches!(self, Aov::Object | Aov::Material)
+    }
+
+    // channel names inside the pass's EXR layer, following the usual conventions
+    fn channel_names(self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::Object | Aov::Material => &["id"]
+        }
+    }
+}
+
+impl std::str::FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL.iter()
+            .find(|aov| aov.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown pass '{}', expected albedo, normal, depth, position, object or material", s))
+    }
+}
+
+// Parses a comma separated list of passes, where "all" selects every pass.
+pub fn parse_list(s: &str) -> Result<Vec<Aov>, String> {
+    if s == "all" {
+        return Ok(Aov::ALL.to_vec());
+    }
+
+    let mut aovs = Vec::new();
+    for name in s.split(',').map(|name| name.trim()) {
+        let aov = name.parse::<Aov>()?;
+        if !aovs.contains(&aov) {
+            aovs.push(aov);
+        }
+    }
+    Ok(aovs)
+}
+
+// Numbers each geometry item's material by the order it first appears in, so
+// items sharing a material also share an index.
+pub fn material_ids(geometry: &[Geometry]) -> Vec<u32> {
+    let mut seen: Vec<*const crate::material::Material> = Vec::new();
+    geometry.iter().map(|item| {
+        let material = item.material() as *const _;
+        match seen.iter().position(|&m| std::ptr::eq(m, material)) {
+            Some(index) => index as u32,
+            None => {
+                seen.push(material);
+                seen.len() as u32 - 1
+            }
+        }
+    }).collect()
+}
+
+// Running totals over the camera rays of one pixel.
+#[derive(Clone, Copy)]
+pub struct AovPixel {
+    albedo: Vector3<f32>,
+    normal: Vector3<f32>,
+    position: Vector3<f32>,
+    depth: f32,
+    hits: u32,
+    samples: u32,
+    object: u32,
+    material: u32
+}
+
+impl AovPixel {
+    pub fn new() -> Self {
+        Self {
+            albedo: Vector3::new(0.0, 0.0, 0.0),
+            normal: Vector3::new(0.0, 0.0, 0.0),
+            position: Vector3::new(0.0, 0.0, 0.0),
+            depth: 0.0,
+            hits: 0,
+            samples: 0,
+            object: 0,
+            material: 0
+        }
+    }
+
+    // Adds the first hit of a camera ray, or None when it left the scene.
+    pub fn add(&mut self, hit: Option<&HitRecord>, material_ids: &[u32]) {
+        self.samples += 1;
+        if let Some(hit) = hit {
+            self.albedo += hit.material.albedo(hit);
+            self.normal += hit.normal;
+            self.position += hit.p;
+            self.depth += hit.t;
+            // averaging indices is meaningless, so the first ray to hit anything decides them
+            if self.hits == 0 {
+                self.object = hit.object as u32 + 1;
+                self.material = material_ids[hit.object] + 1;
+            }
+            self.hits += 1;
+        }
+    }
+}
+
+// The auxiliary passes for every pixel, top row first like the framebuffer.
+pub struct AovBuffers {
+    pub width: usize,
+    pub height: usize,
+    pub albedo: Vec<Vector3<f32>>,
+    pub normal: Vec<Vector3<f32>>,
+    // position and depth are averaged over the rays that hit something, and
+    // depth is infinite where none did
+    pub position: Vec<Vector3<f32>>,
+    pub depth: Vec<f32>,
+    // indices into the geometry list and the list of distinct materials, plus
+    // one so that 0 marks the background
+    pub object: Vec<u32>,
+    pub material: Vec<u32>
+}
+
+impl AovBuffers {
+    pub fn from_pixels(width: usize, height: usize, pixels: &[AovPixel]) -> Self {
+        let per_hit = |sum: Vector3<f32>, p: &AovPixel| if p.hits > 0 { sum / p.hits as f32 } else { sum };
+        Self {
+            width,
+            height,
+            albedo: pixels.iter().map(|p| p.albedo / p.samples.max(1) as f32).collect(),
+            normal: pixels.iter().map(|p| if p.hits > 0 { p.normal.normalize() } else { p.normal }).collect(),
+            position: pixels.iter().map(|p| per_hit(p.position, p)).collect(),
+            depth: pixels.iter().map(|p| if p.hits > 0 { p.depth / p.hits as f32 } else { f32::INFINITY }).collect(),
+            object: pixels.iter().map(|p| p.object).collect(),
+            material: pixels.iter().map(|p| p.material).collect()
+        }
+    }
+
+    // One buffer per channel of the pass, named `<pass>.<channel>` so that they
+    // form a layer when written to a multi-layer EXR.
+    pub fn channels(&self, aov: Aov) -> Vec<(String, Vec<f32>)> {
+        let values = self.values(aov);
+        aov.channel_names().iter().enumerate().map(|(c, channel)| {
+            (format!("{}.{}", aov.name(), channel), values.iter().map(|v| v[c]).collect())
+        }).collect()
+    }
+
+    // The raw values of a pass, with single channel passes repeated across RGB.
+    pub fn to_framebuffer(&self, aov: Aov) -> Framebuffer {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self.values(aov)
+        }
+    }
+
+    // Maps a pass into a viewable 8-bit image: normals from [-1, 1], depth and
+    // position relative to their range in the image (near is bright), and
+    // indices as arbitrary distinct colors.
+    pub fn to_argb(&self, aov: Aov) -> Vec<u32> {
+        let quantize = |c: Vector3<f32>| {
+            let q = |x: f32| (255.0 * x).clamp(0.0, 255.0) as u32;
+            framebuffer::argb(q(c.x), q(c.y), q(c.z))
+        };
+
+        match aov {
+            // albedo is already between 0 and 1, so only gamma encoded like the beauty pass
+            Aov::Albedo => self.to_framebuffer(aov).to_argb(&ToneMap::default()),
+            Aov::Normal => self.normal.iter().map(|&n| quantize(0.5 * (n + Vector3::new(1.0, 1.0, 1.0)))).collect(),
+            Aov::Depth => {
+                let max = self.depth.iter().cloned().filter(|d| d.is_finite()).fold(0.0, f32::max);
+                self.depth.iter().map(|&d| {
+                    let grey = if d.is_finite() && max > 0.0 { 1.0 - d / max } else { 0.0 };
+                    quantize(Vector3::new(grey, grey, grey))
+                }).collect()
+            },
+            Aov::Position => {
+                let hit_positions = || self.position.iter().zip(&self.depth).filter(|(_, d)| d.is_finite()).map(|(p, _)| *p);
+                let min = hit_positions().fold(Vector3::new(f32::MAX, f32::MAX, f32::MAX), |a, p| Vector3::new(a.x.min(p.x), a.y.min(p.y), a.z.min(p.z)));
+                let max = hit_positions().fold(Vector3::new(f32::MIN, f32::MIN, f32::MIN), |a, p| Vector3::new(a.x.max(p.x), a.y.max(p.y), a.z.max(p.z)));
+                let extent = max - min;
+                self.position.iter().zip(&self.depth).map(|(p, d)| {
+                    if !d.is_finite() {
+                        return quantize(Vector3::new(0.0, 0.0, 0.0));
+                    }
+                    let scale = |x: f32, lo: f32, range: f32| if range > 0.0 { (x - lo) / range } else { 0.5 };
+                    quantize(Vector3::new(scale(p.x, min.x, extent.x), scale(p.y, min.y, extent.y), scale(p.z, min.z, extent.z)))
+                }).collect()
+            },
+            Aov::Object | Aov::Material => {
+                let ids = if aov == Aov::Object { &self.object } else { &self.material };
+                ids.iter().map(|&id| if id == 0 { framebuffer::argb(0, 0, 0) } else { id_color(id) }).collect()
+            }
+        }
+    }
+
+    fn values(&self, aov: Aov) -> Vec<Vector3<f32>> {
+        let splat = |x: f32| Vector3::new(x, x, x);
+        match aov {
+            Aov::Albedo => self.albedo.clone(),
+            Aov::Normal => self.normal.clone(),
+            Aov::Position => self.position.clone(),
+            Aov::Depth => self.depth.iter().map(|&d| splat(d)).collect(),
+            Aov::Object => self.object.iter().map(|&id| splat(id as f32)).collect(),
+            Aov::Material => self.material.iter().map(|&id| splat(id as f32)).collect()
+        }
+    }
+}
+
+// Spreads indices around the hue circle by the golden ratio, so neighbouring
+// indices get clearly different colors.
+fn id_color(id: u32) -> u32 {
+    let hue = (id as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x)
+    };
+    // a little desaturated, pure hues are hard to tell apart next to each other
+    let channel = |c: f32| (255.0 * (0.25 + 0.75 * c)) as u32;
+    framebuffer::argb(channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::filter::{ Filter, FilterKind };
+    use crate::material::Material;
+    use crate::renderer::{ self, MisHeuristic, Settings };
+    use crate::sampler::SamplerKind;
+    use crate::texture::Texture;
+    use crate::tile::TileOrder;
+
+    #[test]
+    fn numbers_materials_by_first_use() {
+        let texture = Texture::constant(0.5, 0.5, 0.5);
+        let a = Material::lambertian(&texture);
+        // the same contents, but a different material
+        let b = Material::lambertian(&texture);
+        let c = Material::dielectric(1.5);
+        let at = |x: f32| Vector3::new(x, 0.0, 0.0);
+        let geometry = vec![
+            Geometry::sphere(at(0.0), 1.0, &b),
+            Geometry::sphere(at(1.0), 1.0, &a),
+            Geometry::sphere(at(2.0), 1.0, &b),
+            Geometry::sphere(at(3.0), 1.0, &c),
+            Geometry::sphere(at(4.0), 1.0, &a)
+        ];
+        let ids = material_ids(&geometry);
+        assert_eq!(ids, vec![0, 1, 0, 2, 1]);
+        assert_eq!(material_ids(&geometry), ids);
+    }
+
+    #[test]
+    fn averages_hits_and_keeps_the_first_ids() {
+        let texture = Texture::constant(0.2, 0.4, 0.6);
+        let material = Material::lambertian(&texture);
+        let hit = |t: f32, object: usize| HitRecord {
+            t,
+            p: Vector3::new(0.0, 0.0, -t),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            material: &material,
+            u: 0.0,
+            v: 0.0,
+            object
+        };
+
+        let mut missed = AovPixel::new();
+        missed.add(None, &[]);
+        let mut pixel = AovPixel::new();
+        pixel.add(Some(&hit(2.0, 1)), &[0, 0, 3]);
+        pixel.add(None, &[0, 0, 3]);
+        pixel.add(Some(&hit(4.0, 2)), &[0, 0, 3]);
+        let aovs = AovBuffers::from_pixels(2, 1, &[missed, pixel]);
+
+        assert_eq!(aovs.depth[0], f32::INFINITY);
+        assert_eq!((aovs.object[0], aovs.material[0]), (0, 0));
+        assert_eq!(aovs.depth[1], 3.0);
+        assert_eq!(aovs.position[1], Vector3::new(0.0, 0.0, -3.0));
+        assert_eq!(aovs.normal[1], Vector3::new(0.0, 0.0, 1.0));
+        // albedo counts the miss as black
+        assert!((aovs.albedo[1] - Vector3::new(0.2, 0.4, 0.6) * (2.0 / 3.0)).magnitude() < 1e-6);
+        assert_eq!((aovs.object[1], aovs.material[1]), (2, 1));
+    }
+
+    #[test]
+    fn records_the_first_surface_behind_glass() {
+        let grey = Texture::constant(0.5, 0.5, 0.5);
+        let wall = Material::lambertian(&grey);
+        let glass = Material::dielectric(1.5);
+        // a glass ball filling the view, inside a room the refracted and
+        // reflected rays all end up on
+        let world = vec![
+            Geometry::sphere(Vector3::new(0.0, 0.0, 0.0), 20.0, &wall),
+            Geometry::sphere(Vector3::new(0.0, 0.0, -3.0), 1.0, &glass)
+        ];
+        let camera = Camera::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 1.0, 0.0),
+            10.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let settings = Settings {
+            width: 4,
+            height: 4,
+            num_samples: 8,
+            max_depth: 8,
+            rr_depth: 3,
+            heuristic: MisHeuristic::Power,
+            tile_size: 4,
+            tile_order: TileOrder::Scanline,
+            noise_threshold: None,
+            min_samples: 8,
+            seed: 1,
+            sampler: SamplerKind::Random,
+            filter: Filter::new(FilterKind::Box, 0.5),
+            spectral: false
+        };
+        let render = renderer::draw(camera, &world, &world, &settings, |_| true);
+
+        let aovs = &render.aovs;
+        for i in 0..16 {
+            assert!((2.0..2.05).contains(&aovs.depth[i]), "depth {}", aovs.depth[i]);
+            assert!((aovs.position[i].z + 2.0).abs() < 0.05, "position {:?}", aovs.position[i]);
+            let outward = (aovs.position[i] - Vector3::new(0.0, 0.0, -3.0)).normalize();
+            assert!(aovs.normal[i].dot(outward) > 0.999, "normal {:?} at {:?}", aovs.normal[i], aovs.position[i]);
+            assert_eq!((aovs.object[i], aovs.material[i]), (2, 2));
+        }
+    }
+}
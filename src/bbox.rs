@@ -1,12 +1,12 @@
 use cgmath::Vector3;
 
 use crate::ray::Ray;
-// use crate::hitable::HitRecord;
 
 pub trait Bounded {
   fn bounds(&self, t0: f32, t1: f32) -> BBox;
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct BBox {
   pub min: Vector3<f32>,
   pub max: Vector3<f32>
@@ -35,22 +35,47 @@ impl BBox {
     }
   }
 
-  pub fn did_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+  pub fn centroid(&self) -> Vector3<f32> {
+    0.5 * (self.min + self.max)
+  }
+
+  pub fn surface_area(&self) -> f32 {
+    let d = self.max - self.min;
+    2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+  }
+
+  // index of the axis along which the box is widest
+  pub fn longest_axis(&self) -> usize {
+    let extent = self.max - self.min;
+    if extent.x >= extent.y && extent.x >= extent.z {
+      0
+    } else if extent.y >= extent.z {
+      1
+    } else {
+      2
+    }
+  }
+
+  // distance along the ray at which it enters the box, if it does so within [t_min, t_max]
+  pub fn hit_distance(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+    let mut tmin = t_min;
+    let mut tmax = t_max;
     for i in 0..3 {
-      let inv_d = 1.0 / r.direction[i];
+      let inv_d = r.inv_direction[i];
       let mut t0 = (self.min[i] - r.origin[i]) * inv_d;
       let mut t1 = (self.max[i] - r.origin[i]) * inv_d;
       if inv_d < 0.0 {
         std::mem::swap(&mut t0, &mut t1);
       }
-      let tmin = if t0 > t_min { t0 } else { t_min };
-      let tmax = if t1 < t_max { t1 } else { t_max };
+      // f32::max/min ignore NaN, which shows up for rays lying exactly in a slab plane
+      tmin = tmin.max(t0);
+      tmax = tmax.min(t1);
 
-      if tmax <= tmin {
-        return false;
+      if tmax < tmin {
+        return None;
       }
     }
 
-    true
+    Some(tmin)
   }
-}
\ No newline at end of file
+}
@@ -1,82 +1,469 @@
 use crate::bbox::{ Bounded, BBox };
-use crate::hitable::{ Geometry, HitRecord, hit_list, bounding_box_list };
+use crate::hitable::{ Geometry, Hitable, HitRecord };
 use crate::ray::Ray;
 
-use rand::prelude::*;
+use std::fmt;
+use std::time::{ Duration, Instant };
 
-use std::cmp::Ordering;
+// median split leaves are split until they hold at most this many items
+const MAX_LEAF_SIZE: usize = 4;
+// SAH leaves may hold more when splitting would not pay off, but never more than this
+const MAX_SAH_LEAF_SIZE: usize = 16;
+const SAH_BINS: usize = 12;
+// cost of visiting a node relative to intersecting one item
+const TRAVERSAL_COST: f32 = 0.125;
+// traversal keeps pending nodes on a fixed-size stack, so the tree depth is capped
+const MAX_DEPTH: usize = 48;
 
-// pub struct BVH<T: Bounded> {
-//   geometry: Vec<T>
-// }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitMethod {
+  // split each node at the median centroid along its longest axis
+  Median,
+  // surface area heuristic, evaluated over binned centroids
+  Sah
+}
 
-// impl<T: Bounded> BVH<T> {
-//   pub fn intersect
-// }
+impl std::str::FromStr for SplitMethod {
+  type Err = String;
 
-pub struct BvhNode<'a> {
-  pub bbox: BBox,
-  pub item: Option<&'a Geometry>,
-  // pub items: &'a [Geometry],
-  pub left: Option<Box<BvhNode<'a>>>,
-  pub right: Option<Box<BvhNode<'a>>>
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "median" => Ok(SplitMethod::Median),
+      "sah" => Ok(SplitMethod::Sah),
+      _ => Err(format!("unknown BVH split method '{}', expected 'median' or 'sah'", s))
+    }
+  }
 }
 
-impl BvhNode<'_> {
-  pub fn new(list: &mut [Geometry], t0: f32, t1: f32) -> BvhNode {
-    let scene_bbox = bounding_box_list(list, t0, t1);
+pub struct BvhStats {
+  pub build_time: Duration,
+  pub node_count: usize,
+  pub leaf_count: usize,
+  pub average_leaf_size: f32
+}
 
-    let axis = 3 * random::<f32>() as usize;
-    list.sort_by(|a, b| box_compare(axis, a, b));
+impl fmt::Display for BvhStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "built in {:.3} seconds, {} nodes, {} leaves, {:.2} items per leaf",
+      self.build_time.as_secs_f32(),
+      self.node_count,
+      self.leaf_count,
+      self.average_leaf_size
+    )
+  }
+}
 
-    let len = list.len();
+pub enum BvhNode {
+  Leaf {
+    bbox: BBox,
+    start: usize,
+    count: usize
+  },
+  Interior {
+    bbox: BBox,
+    left: usize,
+    right: usize
+  }
+}
 
-    if len == 1 {
-      Self {
-        bbox: scene_bbox, //bounding_box_list(&list, t0, t1),
-        // items: list,
-        item: Some(&list[0]),
-        left: None,
-        right: None
-      }
-    } else {
-      let (l, r) = list.split_at_mut(len / 2);
-      Self {
-        bbox: scene_bbox, //bounding_box_list(&list, t0, t1),
-        item: None, // list,
-        left: Some(Box::new(BvhNode::new(l, t0, t1))),
-        right: Some(Box::new(BvhNode::new(r, t0, t1)))
-      }
+impl BvhNode {
+  pub fn bbox(&self) -> &BBox {
+    match self {
+      BvhNode::Leaf { bbox, .. } => bbox,
+      BvhNode::Interior { bbox, .. } => bbox
     }
   }
+}
 
-  pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-    if self.bbox.did_hit(r, t_min, t_max) {
-      match &self.item {
-        Some(item) => item.hit(r, t_min, t_max),
-        None => match (&self.left, &self.right) {
-          (Some(left), Some(right)) => match (left.hit(r, t_min, t_max), right.hit(r, t_min, t_max)) {
-            (Some(hit_left), Some(hit_right)) => if hit_left.t < hit_right.t { Some(hit_left) } else { Some(hit_right) },
-            (Some(hit_left), None) => Some(hit_left),
-            (None, Some(hit_right)) => Some(hit_right),
-            (None, None) => None
-          },
-          (Some(left), None) => left.hit(r, t_min, t_max),
-          (None, Some(right)) => right.hit(r, t_min, t_max),
-          (None, None) => None
+// A bounding volume hierarchy over anything that can be boxed. It only stores
+// indices into the caller's item list, so the same tree works for scene
+// geometry and for the triangles of a mesh.
+pub struct Bvh {
+  nodes: Vec<BvhNode>,
+  // item indices, reordered so that every leaf covers a contiguous range
+  order: Vec<usize>,
+  split: SplitMethod,
+  build_time: Duration
+}
+
+impl Bvh {
+  pub fn new(bounds: &[BBox], split: SplitMethod) -> Self {
+    let now = Instant::now();
+    let mut bvh = Self {
+      nodes: Vec::with_capacity(2 * bounds.len()),
+      order: (0..bounds.len()).collect(),
+      split,
+      build_time: Duration::default()
+    };
+
+    if !bounds.is_empty() {
+      let mut order = std::mem::take(&mut bvh.order);
+      bvh.build(bounds, &mut order[..], 0, 0);
+      bvh.order = order;
+    }
+
+    bvh.build_time = now.elapsed();
+    bvh
+  }
+
+  pub fn stats(&self) -> BvhStats {
+    let (leaf_count, leaf_items) = self.nodes.iter().fold((0, 0), |(leaves, items), node| match node {
+      BvhNode::Leaf { count, .. } => (leaves + 1, items + count),
+      BvhNode::Interior { .. } => (leaves, items)
+    });
+
+    BvhStats {
+      build_time: self.build_time,
+      node_count: self.nodes.len(),
+      leaf_count,
+      average_leaf_size: if leaf_count > 0 { leaf_items as f32 / leaf_count as f32 } else { 0.0 }
+    }
+  }
+
+  fn build(&mut self, bounds: &[BBox], items: &mut [usize], offset: usize, depth: usize) -> usize {
+    let bbox = items.iter()
+      .skip(1)
+      .fold(bounds[items[0]], |acc, &i| acc.merge(&bounds[i]));
+
+    let len = items.len();
+    let index = self.nodes.len();
+    let leaf = BvhNode::Leaf { bbox, start: offset, count: len };
+    if len == 1 || depth >= MAX_DEPTH || (self.split == SplitMethod::Median && len <= MAX_LEAF_SIZE) {
+      self.nodes.push(leaf);
+      return index;
+    }
+
+    let centroid_bbox = items.iter()
+      .map(|&i| bounds[i].centroid())
+      .fold(BBox::new(bounds[items[0]].centroid(), bounds[items[0]].centroid()), |acc, c| acc.merge(&BBox::new(c, c)));
+
+    let mid = match self.split {
+      SplitMethod::Median => median_split(bounds, items, &centroid_bbox),
+      SplitMethod::Sah => match sah_split(bounds, items, &bbox, &centroid_bbox) {
+        Some(mid) => mid,
+        None => {
+          self.nodes.push(leaf);
+          return index;
         }
-      };
+      }
     };
 
-    None
+    // reserve this node's slot before the children are appended after it
+    self.nodes.push(BvhNode::Leaf { bbox, start: offset, count: len });
+    let (l, r) = items.split_at_mut(mid);
+    let left = self.build(bounds, l, offset, depth + 1);
+    let right = self.build(bounds, r, offset + mid, depth + 1);
+    self.nodes[index] = BvhNode::Interior { bbox, left, right };
+
+    index
+  }
+
+  // Walks the tree front-to-back, calling `hit_item` with an item index and the
+  // current closest distance for every item in the leaves the ray reaches.
+  pub fn traverse<'a, F>(&self, r: &Ray, t_min: f32, t_max: f32, mut hit_item: F) -> Option<HitRecord<'a>>
+    where F: FnMut(usize, f32, f32) -> Option<HitRecord<'a>> {
+    let mut hit_anything: Option<HitRecord> = None;
+    let mut closest_so_far = t_max;
+
+    let root_t = match self.nodes.first() {
+      Some(root) => root.bbox().hit_distance(r, t_min, t_max)?,
+      None => return None
+    };
+
+    let mut stack = [(0usize, 0.0f32); MAX_DEPTH + 1];
+    stack[0] = (0, root_t);
+    let mut stack_len = 1;
+
+    while stack_len > 0 {
+      stack_len -= 1;
+      let (index, t_enter) = stack[stack_len];
+      // something closer was found since this node was pushed
+      if t_enter > closest_so_far {
+        continue;
+      }
+
+      match &self.nodes[index] {
+        BvhNode::Leaf { start, count, .. } => {
+          for &item in &self.order[*start..*start + *count] {
+            if let Some(hit) = hit_item(item, t_min, closest_so_far) {
+              closest_so_far = hit.t;
+              hit_anything = Some(hit);
+            }
+          }
+        },
+        BvhNode::Interior { left, right, .. } => {
+          let t_left = self.nodes[*left].bbox().hit_distance(r, t_min, closest_so_far);
+          let t_right = self.nodes[*right].bbox().hit_distance(r, t_min, closest_so_far);
+          // push the far child first so the near one is popped next
+          match (t_left, t_right) {
+            (Some(tl), Some(tr)) => {
+              let (near, far) = if tl <= tr { ((*left, tl), (*right, tr)) } else { ((*right, tr), (*left, tl)) };
+              stack[stack_len] = far;
+              stack[stack_len + 1] = near;
+              stack_len += 2;
+            },
+            (Some(tl), None) => {
+              stack[stack_len] = (*left, tl);
+              stack_len += 1;
+            },
+            (None, Some(tr)) => {
+              stack[stack_len] = (*right, tr);
+              stack_len += 1;
+            },
+            (None, None) => {}
+          }
+        }
+      }
+    }
+
+    hit_anything
+  }
+}
+
+// Splits at the median centroid along the axis where the centroids are most
+// spread out, returning the number of items that go to the left child.
+fn median_split(bounds: &[BBox], items: &mut [usize], centroid_bbox: &BBox) -> usize {
+  let axis = centroid_bbox.longest_axis();
+  let mid = items.len() / 2;
+  items.select_nth_unstable_by(mid, |&a, &b| {
+    bounds[a].centroid()[axis].partial_cmp(&bounds[b].centroid()[axis]).unwrap_or(std::cmp::Ordering::Equal)
+  });
+  mid
+}
+
+// Bins the centroids along each axis and partitions at the bin boundary with the
+// lowest surface area heuristic cost. Returns the number of items that go to the
+// left child, or None when making a leaf is cheaper.
+fn sah_split(bounds: &[BBox], items: &mut [usize], bbox: &BBox, centroid_bbox: &BBox) -> Option<usize> {
+  let len = items.len();
+  let bin_of = |i: usize, axis: usize| {
+    let extent = centroid_bbox.max[axis] - centroid_bbox.min[axis];
+    let b = (SAH_BINS as f32 * (bounds[i].centroid()[axis] - centroid_bbox.min[axis]) / extent) as usize;
+    b.min(SAH_BINS - 1)
+  };
+
+  let mut best: Option<(f32, usize, usize)> = None;
+  for axis in 0..3 {
+    if centroid_bbox.max[axis] - centroid_bbox.min[axis] <= 0.0 {
+      continue;
+    }
+
+    let mut counts = [0usize; SAH_BINS];
+    let mut boxes: [Option<BBox>; SAH_BINS] = [None; SAH_BINS];
+    for &i in items.iter() {
+      let b = bin_of(i, axis);
+      counts[b] += 1;
+      boxes[b] = Some(match boxes[b] {
+        Some(bin_box) => bin_box.merge(&bounds[i]),
+        None => bounds[i]
+      });
+    }
+
+    // sweep from the right to get the area and count of everything past each boundary
+    let mut right_area = [0.0f32; SAH_BINS];
+    let mut right_count = [0usize; SAH_BINS];
+    let mut acc: Option<BBox> = None;
+    let mut count = 0;
+    for b in (1..SAH_BINS).rev() {
+      acc = merge_optional(acc, boxes[b]);
+      count += counts[b];
+      right_area[b] = acc.map_or(0.0, |a| a.surface_area());
+      right_count[b] = count;
+    }
+
+    let mut acc: Option<BBox> = None;
+    let mut count = 0;
+    for b in 1..SAH_BINS {
+      acc = merge_optional(acc, boxes[b - 1]);
+      count += counts[b - 1];
+      if count == 0 || right_count[b] == 0 {
+        continue;
+      }
+      let left_area = acc.map_or(0.0, |a| a.surface_area());
+      let cost = left_area * count as f32 + right_area[b] * right_count[b] as f32;
+      if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+        best = Some((cost, axis, b));
+      }
+    }
+  }
+
+  let leaf_cost = len as f32;
+  let (cost, axis, split_bin) = match best {
+    Some((cost, axis, b)) => (TRAVERSAL_COST + cost / bbox.surface_area().max(f32::EPSILON), axis, b),
+    // every centroid coincides, so there is nothing to split on
+    None => return if len <= MAX_SAH_LEAF_SIZE { None } else { Some(median_split(bounds, items, centroid_bbox)) }
+  };
+
+  if cost >= leaf_cost && len <= MAX_SAH_LEAF_SIZE {
+    return None;
+  }
+
+  // partition in place so items left of the chosen boundary come first
+  let mut mid = 0;
+  for k in 0..len {
+    if bin_of(items[k], axis) < split_bin {
+      items.swap(k, mid);
+      mid += 1;
+    }
+  }
+
+  Some(mid)
+}
+
+fn merge_optional(acc: Option<BBox>, other: Option<BBox>) -> Option<BBox> {
+  match (acc, other) {
+    (Some(a), Some(b)) => Some(a.merge(&b)),
+    (a, None) => a,
+    (None, b) => b
   }
 }
 
-fn box_compare(axis: usize, a: &Geometry, b: &Geometry) -> Ordering {
-  let (left_box, right_box) = (a.bounds(0.0, 0.0), b.bounds(0.0, 0.0));
-  if left_box.min[axis] - right_box.min[axis] < 0.0 {
-    Ordering::Less
-  } else {
-    Ordering::Greater
+// The top-level acceleration structure for a scene's geometry.
+pub struct SceneBvh<'a> {
+  bvh: Bvh,
+  items: &'a [Geometry<'a>]
+}
+
+impl<'a> SceneBvh<'a> {
+  pub fn new(items: &'a [Geometry<'a>], t0: f32, t1: f32, split: SplitMethod) -> Self {
+    let bounds: Vec<BBox> = items.iter().map(|item| item.bounds(t0, t1)).collect();
+    Self {
+      bvh: Bvh::new(&bounds, split),
+      items
+    }
+  }
+
+  pub fn stats(&self) -> BvhStats {
+    self.bvh.stats()
+  }
+}
+
+impl Hitable for SceneBvh<'_> {
+  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+    self.bvh.traverse(r, t_min, t_max, |i, t_min, t_max| {
+      self.items[i].hit(r, t_min, t_max).map(|hit| HitRecord { object: i, ..hit })
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::hitable::hit_list;
+  use crate::material::Material;
+  use crate::rng::Pcg32;
+  use crate::texture::Texture;
+
+  use cgmath::Vector3;
+  use rand::Rng;
+
+  fn random_point(rng: &mut Pcg32, scale: f32) -> Vector3<f32> {
+    scale * Vector3::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5)
+  }
+
+  // Checks that every item is in exactly one leaf, inside that leaf's box, and
+  // returns the depth of the deepest leaf.
+  fn check_tree(bvh: &Bvh, bounds: &[BBox]) -> usize {
+    fn walk(bvh: &Bvh, bounds: &[BBox], index: usize, depth: usize, seen: &mut [usize]) -> usize {
+      match &bvh.nodes[index] {
+        BvhNode::Leaf { bbox, start, count } => {
+          assert!(*count > 0, "empty leaf");
+          for &item in &bvh.order[*start..*start + *count] {
+            seen[item] += 1;
+            for axis in 0..3 {
+              assert!(bbox.min[axis] <= bounds[item].min[axis] && bounds[item].max[axis] <= bbox.max[axis]);
+            }
+          }
+          depth
+        },
+        BvhNode::Interior { left, right, .. } => {
+          let left = walk(bvh, bounds, *left, depth + 1, seen);
+          left.max(walk(bvh, bounds, *right, depth + 1, seen))
+        }
+      }
+    }
+
+    let mut seen = vec![0; bounds.len()];
+    let depth = walk(bvh, bounds, 0, 0, &mut seen);
+    assert!(seen.iter().all(|&n| n == 1), "items not in exactly one leaf: {:?}", seen);
+    depth
+  }
+
+  fn unit_box(center: Vector3<f32>) -> BBox {
+    BBox::new(center - Vector3::new(0.5, 0.5, 0.5), center + Vector3::new(0.5, 0.5, 0.5))
+  }
+
+  #[test]
+  fn puts_every_item_in_one_leaf() {
+    let mut rng = Pcg32::new(2, 0);
+    let bounds: Vec<BBox> = (0..500).map(|_| unit_box(random_point(&mut rng, 50.0))).collect();
+    for &split in &[SplitMethod::Median, SplitMethod::Sah] {
+      let depth = check_tree(&Bvh::new(&bounds, split), &bounds);
+      assert!(depth <= MAX_DEPTH);
+    }
+  }
+
+  #[test]
+  fn stops_splitting_at_max_depth() {
+    let mut rng = Pcg32::new(3, 0);
+    let bounds: Vec<BBox> = (0..100).map(|_| unit_box(random_point(&mut rng, 50.0))).collect();
+    for &split in &[SplitMethod::Median, SplitMethod::Sah] {
+      // build as if this were already two levels short of the cap in a bigger tree
+      let mut bvh = Bvh { nodes: Vec::new(), order: Vec::new(), split, build_time: Duration::default() };
+      let mut order: Vec<usize> = (0..bounds.len()).collect();
+      bvh.build(&bounds, &mut order, 0, MAX_DEPTH - 2);
+      bvh.order = order;
+      assert_eq!(check_tree(&bvh, &bounds), 2);
+      assert_eq!(bvh.stats().leaf_count, 4);
+    }
+  }
+
+  #[test]
+  fn stops_on_coincident_centroids() {
+    for &len in &[1, MAX_SAH_LEAF_SIZE, 100] {
+      let bounds = vec![unit_box(Vector3::new(1.0, 2.0, 3.0)); len];
+      for &split in &[SplitMethod::Median, SplitMethod::Sah] {
+        let bvh = Bvh::new(&bounds, split);
+        check_tree(&bvh, &bounds);
+        // SAH can't separate identical boxes, so it keeps small groups in one leaf
+        if split == SplitMethod::Sah && len <= MAX_SAH_LEAF_SIZE {
+          assert_eq!(bvh.stats().leaf_count, 1);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn finds_the_same_closest_hit_as_the_list() {
+    let texture = Texture::constant(0.5, 0.5, 0.5);
+    let material = Material::lambertian(&texture);
+    let mut rng = Pcg32::new(1, 0);
+    let spheres: Vec<Geometry> = (0..200)
+      .map(|_| Geometry::sphere(random_point(&mut rng, 20.0), 0.1 + rng.gen::<f32>(), &material))
+      .collect();
+
+    for &split in &[SplitMethod::Median, SplitMethod::Sah] {
+      let bvh = SceneBvh::new(&spheres, 0.0, 1.0, split);
+      let mut hits = 0;
+      for _ in 0..2000 {
+        // from anywhere around the spheres towards a point among them
+        let origin = random_point(&mut rng, 60.0);
+        let r = Ray::new(origin, random_point(&mut rng, 20.0) - origin, 0.0);
+        let expected = hit_list(&spheres, &r, 0.001, f32::MAX);
+        let found = bvh.hit(&r, 0.001, f32::MAX);
+        match (expected, found) {
+          (Some(e), Some(f)) => {
+            assert_eq!((e.t, e.object), (f.t, f.object));
+            hits += 1;
+          },
+          (None, None) => {},
+          (e, f) => panic!("{:?} split: list hit {:?}, BVH hit {:?}", split, e.map(|h| h.t), f.map(|h| h.t))
+        }
+      }
+      // most rays should hit something, or the comparison proves little
+      assert!(hits > 500, "only {} rays hit", hits);
+    }
   }
 }
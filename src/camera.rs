@@ -1,8 +1,6 @@
 use crate::ray::Ray;
 use crate::util;
 
-use rand::prelude::*;
-
 use cgmath::{
     Vector3,
     InnerSpace
@@ -24,6 +22,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(look_from: Vector3<f32>, look_at: Vector3<f32>, vup: Vector3<f32>,
                vfov: f32, aspect: f32, aperture: f32, focus_dist: f32, time0: f32, time1: f32) -> Self {
         let theta = vfov * TO_RADIANS;
@@ -47,10 +46,12 @@ impl Camera {
         }
     }
     
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let rd = self.lens_radius * util::random_in_unit_disk();
+    // `lens` picks the point on the lens the ray starts from and `time` when
+    // in the shutter interval it does, both from uniform samples.
+    pub fn get_ray(&self, u: f32, v: f32, lens: (f32, f32), time: f32) -> Ray {
+        let rd = self.lens_radius * util::sample_unit_disk(lens);
         let offset = self.u * rd.x + self.v * rd.y;
-        let time = self.time0 + random::<f32>() * (self.time1 - self.time0);
+        let time = self.time0 + time * (self.time1 - self.time0);
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + (u * self.horizontal) + (v * self.vertical) - self.origin - offset,
@@ -0,0 +1,127 @@
+use crate::ray::Ray;
+use crate::material::Material;
+use crate::hitable::{ Geometry, Hitable, HitRecord };
+use crate::bbox::{ Bounded, BBox };
+use crate::rng;
+
+use cgmath::InnerSpace;
+
+// how far past a crossing of the boundary the next one is looked for, in world
+// units, so it doesn't depend on the length of the ray's direction
+const CROSSING_EPSILON: f32 = 1e-4;
+
+// Fog or smoke of the same density everywhere inside a closed boundary. Rays
+// travelling through it scatter at random, more often the denser it is, at a
+// point that then takes `phase` as its material.
+pub struct ConstantMedium<'material> {
+    pub boundary: Box<Geometry<'material>>,
+    // chance of scattering per unit of distance
+    pub density: f32,
+    pub phase: &'material Material<'material>,
+    // mixed into the scattering distances, so they change with the render's seed
+    pub seed: u64
+}
+
+impl<'material> ConstantMedium<'material> {
+    pub fn new(boundary: Geometry<'material>, density: f32, phase: &'material Material, seed: u64) -> Self {
+        Self {
+            boundary: Box::new(boundary),
+            density,
+            phase,
+            seed
+        }
+    }
+}
+
+impl Bounded for ConstantMedium<'_> {
+    fn bounds(&self, t0: f32, t1: f32) -> BBox {
+        self.boundary.bounds(t0, t1)
+    }
+}
+
+impl Hitable for ConstantMedium<'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let length = r.direction.magnitude();
+        let epsilon = CROSSING_EPSILON / length;
+        // distance the ray goes inside before it scatters
+        let mut remaining = -(1.0 - uniform_for(r, self.seed)).ln() / self.density;
+
+        // walk through every stretch of the ray inside the boundary, starting
+        // behind the origin in case it is already inside, so that shapes the
+        // ray goes in and out of several times work too
+        let mut from = -f32::MAX;
+        loop {
+            let enter = self.boundary.hit(r, from, f32::MAX)?;
+            let exit = self.boundary.hit(r, enter.t + epsilon, f32::MAX)?;
+            let start = enter.t.max(t_min);
+            let end = exit.t.min(t_max);
+            if start >= t_max {
+                return None;
+            }
+
+            if end > start {
+                let inside = (end - start) * length;
+                if remaining < inside {
+                    let t = start + remaining / length;
+                    return Some(HitRecord {
+                        t,
+                        p: r.point_at_parameter(t),
+                        // there is no surface, so face the ray
+                        normal: -r.direction / length,
+                        material: self.phase,
+                        u: 0.0,
+                        v: 0.0,
+                        object: 0
+                    });
+                }
+                remaining -= inside;
+            }
+            from = exit.t + epsilon;
+        }
+    }
+}
+
+// Hits don't get a sampler to draw from, so the scattering distance comes from
+// a hash of the ray and the seed instead: the same ray always scatters at the
+// same point, whichever thread or BVH asks, and any other ray or seed gets an
+// unrelated number.
+fn uniform_for(r: &Ray, seed: u64) -> f32 {
+    let words = [r.origin.x, r.origin.y, r.origin.z, r.direction.x, r.direction.y, r.direction.z, r.time];
+    let hash = words.iter().fold(rng::mix(seed), |hash, word| rng::mix(hash ^ word.to_bits() as u64));
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+    use cgmath::Vector3;
+
+    // a ray from z = -5 straight through the middle of a unit sphere, with a
+    // direction of the given length
+    fn through_unit_sphere(density: f32, length: f32) -> Option<f32> {
+        let texture = Texture::constant(1.0, 1.0, 1.0);
+        let phase = Material::isotropic(&texture);
+        let boundary = Geometry::sphere(Vector3::new(0.0, 0.0, 0.0), 1.0, &phase);
+        let medium = ConstantMedium::new(boundary, density, &phase, 3);
+        let r = Ray::new(Vector3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, length), 0.0);
+        medium.hit(&r, 0.001 / length, f32::MAX).map(|hit| hit.p.z)
+    }
+
+    #[test]
+    fn thin_media_let_rays_through() {
+        for &length in &[1e-3, 1.0, 1e5] {
+            assert_eq!(through_unit_sphere(1e-9, length), None, "a ray of length {} scattered", length);
+        }
+    }
+
+    #[test]
+    fn dense_media_scatter_inside_the_boundary() {
+        for &length in &[1e-3, 1.0, 1e5] {
+            match through_unit_sphere(1e6, length) {
+                Some(z) => assert!((-1.0 - 1e-3..-0.99).contains(&z), "a ray of length {} scattered at z = {}", length, z),
+                None => panic!("a ray of length {} went through", length)
+            }
+        }
+    }
+}
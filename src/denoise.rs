@@ -0,0 +1,160 @@
+use cgmath::{ ElementWise, InnerSpace, Vector3, dot };
+use rayon::prelude::*;
+
+use crate::aov::AovBuffers;
+use crate::framebuffer::Framebuffer;
+
+// how far around a pixel, in pixels, others are averaged into it
+const WINDOW_RADIUS: isize = 7;
+// size of the neighbourhoods compared to decide whether two pixels look alike
+const PATCH_RADIUS: isize = 1;
+// albedo is clamped to at least this before dividing it out, so that black
+// channels don't blow up
+const MIN_ALBEDO: f32 = 0.01;
+
+// How different two pixels may be before they stop counting as the same
+// surface: in tone mapped color, in albedo, in 1 - the cosine between normals,
+// and in depth relative to the nearer one.
+const COLOR_SIGMA: f32 = 0.1;
+const ALBEDO_SIGMA: f32 = 0.1;
+const NORMAL_SIGMA: f32 = 0.1;
+const DEPTH_SIGMA: f32 = 0.05;
+
+// Removes noise from a render with a non-local means filter guided by the
+// auxiliary passes: every pixel becomes a weighted average of the pixels around
+// it, where pixels count for more the more their neighbourhoods look alike and
+// the more alike their albedo, normal and depth are, so that edges and texture
+// survive. The filter works on the light arriving at the surfaces, the beauty
+// divided by the albedo, and multiplies the albedo back in afterwards.
+// `strength` scales how different the colors of pixels may be and still be
+// averaged together.
+pub fn denoise(beauty: &Framebuffer, aovs: &AovBuffers, strength: f32) -> Framebuffer {
+    let (width, height) = (beauty.width, beauty.height);
+    // every pixel is divided and multiplied by the same clamped albedo, so
+    // pixels on either side of the clamp still average consistently
+    let clamped = |albedo: Vector3<f32>| albedo.map(|a| a.max(MIN_ALBEDO));
+    let demodulate = |c: Vector3<f32>, albedo: Vector3<f32>| c.div_element_wise(clamped(albedo));
+    let irradiance: Vec<Vector3<f32>> = beauty.pixels.iter().zip(&aovs.albedo).map(|(&c, &a)| demodulate(c, a)).collect();
+    // compared in a compressed range, so that bright pixels don't drown out
+    // the differences everywhere else
+    let compressed: Vec<Vector3<f32>> = irradiance.iter().map(|c| c.map(|x| x.max(0.0) / (1.0 + x.max(0.0)))).collect();
+
+    let color_scale = 1.0 / (strength * strength * COLOR_SIGMA * COLOR_SIGMA);
+    let spatial_sigma = WINDOW_RADIUS as f32 / 2.0;
+    let patch_size = ((2 * PATCH_RADIUS + 1) * (2 * PATCH_RADIUS + 1)) as f32;
+    let at = |x: isize, y: isize| y.clamp(0, height as isize - 1) as usize * width + x.clamp(0, width as isize - 1) as usize;
+
+    let mut pixels = vec![Vector3::new(0.0, 0.0, 0.0); width * height];
+    pixels.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let y = y as isize;
+        for (x, out) in row.iter_mut().enumerate() {
+            let x = x as isize;
+            let p = at(x, y);
+            let mut sum = Vector3::new(0.0, 0.0, 0.0);
+            let mut total = 0.0;
+
+            for qy in (y - WINDOW_RADIUS).max(0)..=(y + WINDOW_RADIUS).min(height as isize - 1) {
+                for qx in (x - WINDOW_RADIUS).max(0)..=(x + WINDOW_RADIUS).min(width as isize - 1) {
+                    let q = at(qx, qy);
+                    // surfaces and background never mix
+                    if aovs.depth[p].is_finite() != aovs.depth[q].is_finite() {
+                        continue;
+                    }
+
+                    let mut patch_distance = 0.0;
+                    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+                        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+                            let d = compressed[at(x + dx, y + dy)] - compressed[at(qx + dx, qy + dy)];
+                            patch_distance += d.magnitude2() / 3.0;
+                        }
+                    }
+                    let color = patch_distance / patch_size * color_scale;
+
+                    let albedo = (aovs.albedo[p] - aovs.albedo[q]).magnitude2() / (ALBEDO_SIGMA * ALBEDO_SIGMA);
+                    let normal = if aovs.depth[p].is_finite() {
+                        (1.0 - dot(aovs.normal[p], aovs.normal[q])).max(0.0) / NORMAL_SIGMA
+                    } else {
+                        0.0
+                    };
+                    let depth = if aovs.depth[p].is_finite() {
+                        let relative = (aovs.depth[p] - aovs.depth[q]) / (DEPTH_SIGMA * aovs.depth[p].min(aovs.depth[q]).max(1e-4));
+                        relative * relative
+                    } else {
+                        0.0
+                    };
+                    let r2 = ((qx - x) * (qx - x) + (qy - y) * (qy - y)) as f32;
+                    let spatial = r2 / (2.0 * spatial_sigma * spatial_sigma);
+
+                    let weight = (-(color + albedo + normal + depth + spatial)).exp();
+                    sum += weight * irradiance[q];
+                    total += weight;
+                }
+            }
+
+            let filtered = if total > 0.0 { sum / total } else { irradiance[p] };
+            *out = filtered.mul_element_wise(clamped(aovs.albedo[p]));
+        }
+    });
+
+    Framebuffer {
+        width,
+        height,
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+    use rand::Rng;
+
+    fn aovs(width: usize, height: usize, albedo: Vector3<f32>) -> AovBuffers {
+        let n = width * height;
+        AovBuffers {
+            width,
+            height,
+            albedo: vec![albedo; n],
+            normal: vec![Vector3::new(0.0, 0.0, 1.0); n],
+            position: vec![Vector3::new(0.0, 0.0, -2.0); n],
+            depth: vec![2.0; n],
+            object: vec![1; n],
+            material: vec![1; n]
+        }
+    }
+
+    #[test]
+    fn leaves_constant_images_alone() {
+        let (width, height) = (12, 9);
+        let color = Vector3::new(0.2, 0.7, 1.5);
+        let beauty = Framebuffer { width, height, pixels: vec![color; width * height] };
+        let mut aovs = aovs(width, height, Vector3::new(0.5, 0.5, 0.25));
+        // the background on the right doesn't mix in, but it is constant too
+        for y in 0..height {
+            for x in width / 2..width {
+                aovs.depth[y * width + x] = f32::INFINITY;
+            }
+        }
+        for &strength in &[0.5, 1.0, 4.0] {
+            let denoised = denoise(&beauty, &aovs, strength);
+            for pixel in &denoised.pixels {
+                assert!((pixel - color).magnitude() < 1e-5, "{:?} became {:?}", color, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn black_albedo_stays_finite() {
+        let (width, height) = (10, 10);
+        let mut rng = Pcg32::new(3, 0);
+        let pixels = (0..width * height).map(|_| Vector3::new(rng.gen(), rng.gen(), 0.0)).collect();
+        let beauty = Framebuffer { width, height, pixels };
+        let mut aovs = aovs(width, height, Vector3::new(0.0, 0.0, 0.0));
+        aovs.albedo[0] = Vector3::new(1.0, 0.0, 0.5);
+        let denoised = denoise(&beauty, &aovs, 1.0);
+        for pixel in &denoised.pixels {
+            assert!(pixel.x.is_finite() && pixel.y.is_finite() && pixel.z.is_finite(), "{:?}", pixel);
+            assert_eq!(pixel.z, 0.0);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// A minimal OpenEXR writer: single part, scanline, uncompressed. That is all
+// compositing packages need to read our renders, and it avoids pulling in a
+// full EXR implementation.
+
+use std::io::{ self, Write };
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelType {
+    Half,
+    Float
+}
+
+impl PixelType {
+    // the type's code in the channel list, and how many bytes a sample takes
+    fn id_and_size(self) -> (i32, usize) {
+        match self {
+            PixelType::Half => (1, 2),
+            PixelType::Float => (2, 4)
+        }
+    }
+}
+
+impl std::str::FromStr for PixelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half" => Ok(PixelType::Half),
+            "float" => Ok(PixelType::Float),
+            _ => Err(format!("unknown EXR pixel type '{}', expected 'half' or 'float'", s))
+        }
+    }
+}
+
+// One named channel, e.g. "R" or "albedo.G", with a value per pixel, top row
+// first, stored as `pixel_type`.
+pub struct Channel<'a> {
+    pub name: String,
+    pub data: &'a [f32],
+    pub pixel_type: PixelType
+}
+
+pub fn write<W: Write>(w: &mut W, width: usize, height: usize, channels: &mut [Channel]) -> io::Result<()> {
+    // readers expect the channel list in alphabetical order, and the pixel data follows it
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // magic number and version 2, with no flags set for a single part scanline file
+    w.write_all(&20000630i32.to_le_bytes())?;
+    w.write_all(&2i32.to_le_bytes())?;
+
+    let mut chlist = Vec::new();
+    for channel in channels.iter() {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&channel.pixel_type.id_and_size().0.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = Vec::new();
+    for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute(w, "channels", "chlist", &chlist)?;
+    attribute(w, "compression", "compression", &[0])?;
+    attribute(w, "dataWindow", "box2i", &window)?;
+    attribute(w, "displayWindow", "box2i", &window)?;
+    // increasing y
+    attribute(w, "lineOrder", "lineOrder", &[0])?;
+    attribute(w, "pixelAspectRatio", "float", &1.0f32.to_le_bytes())?;
+    attribute(w, "screenWindowCenter", "v2f", &[0; 8])?;
+    attribute(w, "screenWindowWidth", "float", &1.0f32.to_le_bytes())?;
+    w.write_all(&[0])?;
+
+    // without compression every block is a single scanline of known size, so the
+    // offset table can be written up front
+    let line_size: usize = channels.iter().map(|channel| width * channel.pixel_type.id_and_size().1).sum();
+    let block_size = 8 + line_size;
+    let header_size = 8 + attributes_size(&chlist, window.len()) + 1;
+    let table_size = 8 * height;
+    for y in 0..height {
+        let offset = (header_size + table_size + y * block_size) as u64;
+        w.write_all(&offset.to_le_bytes())?;
+    }
+
+    let mut line = Vec::with_capacity(line_size);
+    for y in 0..height {
+        line.clear();
+        for channel in channels.iter() {
+            for &value in &channel.data[y * width..(y + 1) * width] {
+                match channel.pixel_type {
+                    PixelType::Half => line.extend_from_slice(&f32_to_half(value).to_le_bytes()),
+                    PixelType::Float => line.extend_from_slice(&value.to_le_bytes())
+                }
+            }
+        }
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&(line_size as i32).to_le_bytes())?;
+        w.write_all(&line)?;
+    }
+
+    Ok(())
+}
+
+fn attribute<W: Write>(w: &mut W, name: &str, type_name: &str, value: &[u8]) -> io::Result<()> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(type_name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&(value.len() as i32).to_le_bytes())?;
+    w.write_all(value)
+}
+
+// size of the attributes written by `write`, each being name, type, size and value
+fn attributes_size(chlist: &[u8], window_size: usize) -> usize {
+    let sizes = [
+        ("channels", "chlist", chlist.len()),
+        ("compression", "compression", 1),
+        ("dataWindow", "box2i", window_size),
+        ("displayWindow", "box2i", window_size),
+        ("lineOrder", "lineOrder", 1),
+        ("pixelAspectRatio", "float", 4),
+        ("screenWindowCenter", "v2f", 8),
+        ("screenWindowWidth", "float", 4)
+    ];
+    sizes.iter().map(|(name, type_name, size)| name.len() + 1 + type_name.len() + 1 + 4 + size).sum()
+}
+
+// IEEE 754 half precision, rounding to nearest even. Values too large for a
+// half become infinity.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // keep NaN a NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        // subnormal half, or too small and flushed to zero
+        if half_exponent < -10 {
+            return sign;
+        }
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let round_bit = 1 << (shift - 1);
+        let mut half = m >> shift;
+        if m & round_bit != 0 && m & (3 * round_bit - 1) != 0 {
+            half += 1;
+        }
+        return sign | half as u16;
+    }
+
+    let mut half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    // a carry out of the mantissa correctly bumps the exponent
+    if mantissa & 0x1000 != 0 && mantissa & 0x2fff != 0 {
+        half += 1;
+    }
+    sign | half as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn i32_at(bytes: &[u8], at: usize) -> i32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        i32::from_le_bytes(word)
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[at..at + 8]);
+        u64::from_le_bytes(word)
+    }
+
+    #[test]
+    fn converts_to_half() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(0.1), 0x2e66);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        // too large, including values that only round up to infinity
+        assert_eq!(f32_to_half(65520.0), 0x7c00);
+        assert_eq!(f32_to_half(1e6), 0x7c00);
+        assert_eq!(f32_to_half(f32::NEG_INFINITY), 0xfc00);
+        let nan = f32_to_half(f32::NAN);
+        assert!(nan & 0x7c00 == 0x7c00 && nan & 0x3ff != 0);
+    }
+
+    #[test]
+    fn rounds_to_nearest_even() {
+        // halfway between 1 and the next half goes down to the even mantissa
+        assert_eq!(f32_to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        // a mantissa carry moves on to the next exponent
+        assert_eq!(f32_to_half(2.0 - 2f32.powi(-12)), 0x4000);
+    }
+
+    #[test]
+    fn converts_subnormals() {
+        assert_eq!(f32_to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_half(2f32.powi(-15)), 0x0200);
+        assert_eq!(f32_to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_half(1.5 * 2f32.powi(-25)), 0x0001);
+        assert_eq!(f32_to_half(-2f32.powi(-30)), 0x8000);
+    }
+
+    #[test]
+    fn parses_pixel_types() {
+        assert_eq!("half".parse::<PixelType>(), Ok(PixelType::Half));
+        assert_eq!("float".parse::<PixelType>(), Ok(PixelType::Float));
+        assert!("double".parse::<PixelType>().is_err());
+    }
+
+    #[test]
+    fn writes_header_offsets_and_lines() {
+        let depth = [1.0, 2.0, 3.0, 4.0];
+        let alpha = [0.5, 1.0, 0.0, -2.0];
+        let mut channels = [
+            Channel { name: String::from("Z"), data: &depth, pixel_type: PixelType::Float },
+            Channel { name: String::from("A"), data: &alpha, pixel_type: PixelType::Half }
+        ];
+        let mut bytes = Vec::new();
+        write(&mut bytes, 2, 2, &mut channels).unwrap();
+
+        assert_eq!(i32_at(&bytes, 0), 20000630);
+        assert_eq!(i32_at(&bytes, 4), 2);
+
+        // the channel list comes first, sorted by name
+        let chlist_start = 8 + "channels\0chlist\0".len();
+        assert_eq!(&bytes[8..chlist_start], b"channels\0chlist\0");
+        assert_eq!(i32_at(&bytes, chlist_start), 2 * (2 + 16) + 1);
+        let chlist = &bytes[chlist_start + 4..];
+        assert_eq!(&chlist[..2], b"A\0");
+        assert_eq!(i32_at(chlist, 2), 1);
+        assert_eq!(&chlist[18..20], b"Z\0");
+        assert_eq!(i32_at(chlist, 20), 2);
+
+        // each line holds 2 halves and 2 floats, after its y and size
+        let line_size = 2 * 2 + 2 * 4;
+        let first = u64_at(&bytes, bytes.len() - 2 * (8 + line_size) - 16) as usize;
+        let second = u64_at(&bytes, bytes.len() - 2 * (8 + line_size) - 8) as usize;
+        assert_eq!(first, bytes.len() - 2 * (8 + line_size));
+        assert_eq!(second, first + 8 + line_size);
+        // the header's closing null comes right before the offset table
+        assert_eq!(bytes[first - 16 - 1], 0);
+
+        for (y, &offset) in [first, second].iter().enumerate() {
+            assert_eq!(i32_at(&bytes, offset), y as i32);
+            assert_eq!(i32_at(&bytes, offset + 4) as usize, line_size);
+        }
+        let line = &bytes[second + 8..];
+        assert_eq!(&line[..4], &[0x00, 0x00, 0x00, 0xc0]);
+        assert_eq!(&line[4..8], &3.0f32.to_le_bytes());
+        assert_eq!(&line[8..12], &4.0f32.to_le_bytes());
+    }
+}
@@ -0,0 +1,338 @@
+use cgmath::Vector3;
+
+use std::f32::consts;
+
+use crate::tile::Tile;
+
+// The shape samples are spread over the pixels around them with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    // every sample counts fully towards the pixel it's in and nothing else
+    Box,
+    // weights falling off linearly from the sample
+    Tent,
+    Gaussian,
+    // Mitchell-Netravali with B = C = 1/3, sharper than a Gaussian
+    Mitchell,
+    // a windowed sinc, the sharpest, but prone to ringing around edges
+    Lanczos
+}
+
+impl FilterKind {
+    // Radius, in pixels, used when none is given.
+    pub fn default_radius(self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0
+        }
+    }
+}
+
+impl std::str::FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(format!("unknown filter '{}', expected 'box', 'tent', 'gaussian', 'mitchell' or 'lanczos'", s))
+        }
+    }
+}
+
+// Narrower filters would miss samples near the edges of pixels entirely.
+pub const MIN_RADIUS: f32 = 0.5;
+
+// Below this fraction of the weight that reached a pixel, what is left after
+// the negative lobes cancel out is mostly rounding error, and dividing by it
+// would blow the pixel up.
+const MIN_NET_WEIGHT: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    // how far from a sample, in pixels, pixels still get some of it
+    pub radius: f32
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: f32) -> Self {
+        Self {
+            kind,
+            radius: radius.max(MIN_RADIUS)
+        }
+    }
+
+    // Weight of a sample (dx, dy) pixels away from a pixel's center. The
+    // filters are separable, so this is the product of two 1D weights.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: f32) -> f32 {
+        let r = self.radius;
+        match self.kind {
+            // half open, so a sample on the edge between pixels only counts once
+            FilterKind::Box => if -r <= x && x < r { 1.0 } else { 0.0 },
+            FilterKind::Tent => (r - x.abs()).max(0.0),
+            FilterKind::Gaussian => {
+                // a standard deviation of a third of the radius, shifted down so
+                // it reaches zero at the radius instead of being cut off there
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(r)).max(0.0)
+            },
+            FilterKind::Mitchell => {
+                // the standard kernel spans [-2, 2]
+                let x = (2.0 * x / r).abs();
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let value = if x < 1.0 {
+                    (12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)
+                } else if x < 2.0 {
+                    (-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+                } else {
+                    0.0
+                };
+                value / 6.0
+            },
+            FilterKind::Lanczos => if x.abs() < r { sinc(x) * sinc(x / r) } else { 0.0 }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (consts::PI * x).sin() / (consts::PI * x)
+    }
+}
+
+// Weighted sums of the samples that fell inside a tile, spread over the tile
+// and the pixels around it within the filter's reach.
+pub struct SplatBuffer {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    sums: Vec<Vector3<f32>>,
+    weights: Vec<f32>,
+    abs_weights: Vec<f32>,
+    // plain sums and counts of the samples taken inside each pixel
+    box_sums: Vec<Vector3<f32>>,
+    box_counts: Vec<u32>
+}
+
+impl SplatBuffer {
+    pub fn new(tile: &Tile, filter: &Filter, image_width: usize, image_height: usize) -> Self {
+        let reach = filter.radius.ceil() as usize;
+        let x = tile.x.saturating_sub(reach);
+        let y = tile.y.saturating_sub(reach);
+        let width = (tile.x + tile.width + reach).min(image_width) - x;
+        let height = (tile.y + tile.height + reach).min(image_height) - y;
+        Self {
+            x,
+            y,
+            width,
+            height,
+            sums: vec![Vector3::new(0.0, 0.0, 0.0); width * height],
+            weights: vec![0.0; width * height],
+            abs_weights: vec![0.0; width * height],
+            box_sums: vec![Vector3::new(0.0, 0.0, 0.0); width * height],
+            box_counts: vec![0; width * height]
+        }
+    }
+
+    // Adds a sample taken at (x, y) in pixels from the top left of the image,
+    // where pixel centers are at half integers.
+    pub fn add(&mut self, filter: &Filter, x: f32, y: f32, sample: Vector3<f32>) {
+        let (own_x, own_y) = (x.floor() as usize, y.floor() as usize);
+        if (self.x..self.x + self.width).contains(&own_x) && (self.y..self.y + self.height).contains(&own_y) {
+            let index = (own_y - self.y) * self.width + own_x - self.x;
+            self.box_sums[index] += sample;
+            self.box_counts[index] += 1;
+        }
+
+        let x_range = self.pixels_within(x - filter.radius, x + filter.radius, self.x, self.width);
+        let y_range = self.pixels_within(y - filter.radius, y + filter.radius, self.y, self.height);
+        for py in y_range {
+            for px in x_range.clone() {
+                let weight = filter.weight(x - (px as f32 + 0.5), y - (py as f32 + 0.5));
+                if weight != 0.0 {
+                    let index = (py - self.y) * self.width + px - self.x;
+                    self.sums[index] += weight * sample;
+                    self.weights[index] += weight;
+                    self.abs_weights[index] += weight.abs();
+                }
+            }
+        }
+    }
+
+    // Pixels, clipped to the buffer, whose centers lie between `from` and `to`.
+    fn pixels_within(&self, from: f32, to: f32, start: usize, length: usize) -> std::ops::Range<usize> {
+        let first = (from - 0.5).ceil().max(start as f32) as usize;
+        let last = ((to - 0.5).floor() + 1.0).clamp(start as f32, (start + length) as f32) as usize;
+        first..last.max(first)
+    }
+
+    // Adds the buffer into the image-wide totals.
+    pub fn accumulate(&self, splats: &mut Splats) {
+        for row in 0..self.height {
+            let start = (self.y + row) * splats.width + self.x;
+            let range = row * self.width..(row + 1) * self.width;
+            for i in 0..self.width {
+                splats.sums[start + i] += self.sums[range.start + i];
+                splats.weights[start + i] += self.weights[range.start + i];
+                splats.abs_weights[start + i] += self.abs_weights[range.start + i];
+                splats.box_sums[start + i] += self.box_sums[range.start + i];
+                splats.box_counts[start + i] += self.box_counts[range.start + i];
+            }
+        }
+    }
+}
+
+// What all the tiles splatted, summed over the whole image.
+pub struct Splats {
+    width: usize,
+    sums: Vec<Vector3<f32>>,
+    weights: Vec<f32>,
+    abs_weights: Vec<f32>,
+    box_sums: Vec<Vector3<f32>>,
+    box_counts: Vec<u32>
+}
+
+impl Splats {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            sums: vec![Vector3::new(0.0, 0.0, 0.0); width * height],
+            weights: vec![0.0; width * height],
+            abs_weights: vec![0.0; width * height],
+            box_sums: vec![Vector3::new(0.0, 0.0, 0.0); width * height],
+            box_counts: vec![0; width * height]
+        }
+    }
+
+    // The weighted average of the samples around a pixel. Where negative
+    // lobes leave a pixel next to no weight, the plain average of the samples
+    // inside it stands in.
+    pub fn pixel(&self, index: usize) -> Vector3<f32> {
+        if self.weights[index] > MIN_NET_WEIGHT * self.abs_weights[index] {
+            self.sums[index] / self.weights[index]
+        } else if self.box_counts[index] > 0 {
+            self.box_sums[index] / self.box_counts[index] as f32
+        } else {
+            Vector3::new(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+    use crate::rng::Pcg32;
+    use rand::Rng;
+
+    const KINDS: [FilterKind; 5] = [FilterKind::Box, FilterKind::Tent, FilterKind::Gaussian, FilterKind::Mitchell, FilterKind::Lanczos];
+
+    #[test]
+    fn box_and_tent_weights_sum_to_one() {
+        let mut rng = Pcg32::new(1, 0);
+        for &kind in &[FilterKind::Box, FilterKind::Tent] {
+            let filter = Filter::new(kind, kind.default_radius());
+            for _ in 0..1000 {
+                // a sample anywhere in the pixel centered on the origin
+                let (x, y) = (rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5);
+                let mut total = 0.0;
+                for py in -3..=3 {
+                    for px in -3..=3 {
+                        total += filter.weight(x - px as f32, y - py as f32);
+                    }
+                }
+                assert!((total - 1.0).abs() < 1e-5, "{:?} weights sum to {}", kind, total);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_vanish_at_the_radius() {
+        for &kind in &[FilterKind::Gaussian, FilterKind::Mitchell, FilterKind::Lanczos] {
+            for &radius in &[1.0, 2.0, 3.5] {
+                let filter = Filter::new(kind, radius);
+                assert_eq!(filter.weight(radius, 0.0), 0.0);
+                assert_eq!(filter.weight(0.0, -radius), 0.0);
+                assert!(filter.weight(radius - 1e-3, 0.0).abs() < 1e-3, "{:?} jumps at its radius", kind);
+                assert!(filter.weight(0.0, 0.0) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn clamps_narrow_filters() {
+        for &kind in &KINDS {
+            let filter = Filter::new(kind, 0.1);
+            assert_eq!(filter.radius, MIN_RADIUS);
+        }
+
+        // even a sample in the corner of a pixel still reaches it
+        let filter = Filter::new(FilterKind::Tent, 0.0);
+        let tile = Tile { x: 0, y: 0, width: 2, height: 2 };
+        let mut buffer = SplatBuffer::new(&tile, &filter, 2, 2);
+        let mut splats = Splats::new(2, 2);
+        buffer.add(&filter, 1.99, 1.99, Vector3::new(1.0, 2.0, 3.0));
+        buffer.accumulate(&mut splats);
+        assert_eq!(splats.pixel(3), Vector3::new(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn averages_constant_images_exactly() {
+        let mut rng = Pcg32::new(2, 0);
+        let value = Vector3::new(0.25, 0.5, 2.0);
+        for &kind in &KINDS {
+            let filter = Filter::new(kind, kind.default_radius());
+            let (width, height) = (8, 6);
+            let mut splats = Splats::new(width, height);
+            let tile = Tile { x: 0, y: 0, width, height };
+            let mut buffer = SplatBuffer::new(&tile, &filter, width, height);
+            for _ in 0..4000 {
+                let (x, y) = (rng.gen::<f32>() * width as f32, rng.gen::<f32>() * height as f32);
+                buffer.add(&filter, x, y, value);
+            }
+            buffer.accumulate(&mut splats);
+            for index in 0..width * height {
+                let pixel = splats.pixel(index);
+                assert!((pixel - value).magnitude() < 1e-4, "{:?} gave {:?}", kind, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_box_average_when_the_weights_cancel() {
+        let mut splats = Splats::new(3, 1);
+        // positive and negative lobes that nearly cancel
+        splats.sums[0] = Vector3::new(1e-3, 1e-3, 1e-3);
+        splats.weights[0] = 1e-6;
+        splats.abs_weights[0] = 2.0;
+        splats.box_sums[0] = Vector3::new(3.0, 6.0, 9.0);
+        splats.box_counts[0] = 3;
+        assert_eq!(splats.pixel(0), Vector3::new(1.0, 2.0, 3.0));
+
+        // a net weight that is a fair share of the total is used as is
+        splats.sums[1] = Vector3::new(0.5, 0.5, 0.5);
+        splats.weights[1] = 0.5;
+        splats.abs_weights[1] = 2.0;
+        splats.box_sums[1] = Vector3::new(3.0, 6.0, 9.0);
+        splats.box_counts[1] = 3;
+        assert_eq!(splats.pixel(1), Vector3::new(1.0, 1.0, 1.0));
+
+        // nothing at all
+        assert_eq!(splats.pixel(2), Vector3::new(0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,35 @@
+use cgmath::Vector3;
+
+use crate::tonemap::ToneMap;
+use crate::util;
+
+// Linear radiance for every pixel of the image, top row first.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vector3<f32>>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Vector3::new(0.0, 0.0, 0.0); width * height]
+        }
+    }
+
+    // Tone maps, sRGB encodes and quantizes to the 8-bit ARGB layout minifb
+    // displays.
+    pub fn to_argb(&self, tone_map: &ToneMap) -> Vec<u32> {
+        let encode = |x: f32| (255.0 * util::linear_to_srgb(x.clamp(0.0, 1.0)) + 0.5) as u32;
+        self.pixels.iter().map(|&col| {
+            let col = tone_map.apply(col);
+            argb(encode(col[0]), encode(col[1]), encode(col[2]))
+        }).collect()
+    }
+}
+
+pub fn argb(r: u32, g: u32, b: u32) -> u32 {
+    255 << 24 | r << 16 | g << 8 | b
+}
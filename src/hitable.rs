@@ -3,18 +3,20 @@ use crate::material::Material;
 use crate::sphere::Sphere;
 use crate::mesh::Mesh;
 use crate::moving_sphere::MovingSphere;
+use crate::constant_medium::ConstantMedium;
 use crate::bbox::{ Bounded, BBox };
 
 use cgmath::Vector3;
 
 pub trait Hitable {
-  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
 }
 
 pub enum Geometry<'material> {
     Sphere(Sphere<'material>),
     MovingSphere(MovingSphere<'material>),
-    Mesh(Mesh<'material>)
+    Mesh(Mesh<'material>),
+    ConstantMedium(ConstantMedium<'material>)
 }
 
 impl<'material> Geometry<'material> {
@@ -29,6 +31,21 @@ impl<'material> Geometry<'material> {
     pub fn mesh(vertices: Vec<f32>, indices: Vec<usize>, material: &'material Material) -> Geometry<'material> {
         Geometry::Mesh(Mesh::new(vertices, indices, material))
     }
+
+    // Fills `boundary` with a medium of the given density, which scatters with
+    // `phase`, normally an isotropic material. `seed` varies where rays scatter.
+    pub fn constant_medium(boundary: Geometry<'material>, density: f32, phase: &'material Material, seed: u64) -> Geometry<'material> {
+        Geometry::ConstantMedium(ConstantMedium::new(boundary, density, phase, seed))
+    }
+
+    pub fn material(&self) -> &'material Material<'material> {
+        match self {
+            Geometry::Sphere(s) => s.material,
+            Geometry::MovingSphere(ms) => ms.material,
+            Geometry::Mesh(m) => m.material,
+            Geometry::ConstantMedium(cm) => cm.phase
+        }
+    }
 }
 
 impl Bounded for Geometry<'_> {
@@ -36,17 +53,19 @@ impl Bounded for Geometry<'_> {
         match self {
             Geometry::Sphere(s) => s.bounds(t0, t1),
             Geometry::MovingSphere(ms) => ms.bounds(t0, t1),
-            Geometry::Mesh(m) => m.bounds(t0, t1)
+            Geometry::Mesh(m) => m.bounds(t0, t1),
+            Geometry::ConstantMedium(cm) => cm.bounds(t0, t1)
         }
     }
 }
 
 impl Hitable for Geometry<'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         match self {
             Geometry::Sphere(s) => s.hit(r, t_min, t_max),
             Geometry::MovingSphere(ms) => ms.hit(r, t_min, t_max),
-            Geometry::Mesh(m) => m.hit(r, t_min, t_max)
+            Geometry::Mesh(m) => m.hit(r, t_min, t_max),
+            Geometry::ConstantMedium(cm) => cm.hit(r, t_min, t_max)
         }
     }
 }
@@ -57,49 +76,27 @@ pub struct HitRecord<'material> {
     pub normal: Vector3<f32>,
     pub material: &'material Material<'material>,
     pub u: f32,
-    pub v: f32
-}
-
-pub fn bounding_box_list<'world>(items: &'world [Geometry], t0: f32, t1: f32) -> BBox {
-    let min = items.iter().fold(
-        Vector3::new(std::f32::MAX, std::f32::MAX, std::f32::MAX),
-        |acc, item| {
-            let bbox = item.bounds(t0, t1);
-            Vector3::new(
-                acc[0].min(bbox.min[0]),
-                acc[1].min(bbox.min[1]),
-                acc[2].min(bbox.min[2])
-            )
-        }
-    );
-
-    let max = items.iter().fold(
-        Vector3::new(std::f32::MIN, std::f32::MIN, std::f32::MIN),
-        |acc, item| {
-            let bbox = item.bounds(t0, t1);
-            Vector3::new(
-                acc[0].max(bbox.max[0]),
-                acc[1].max(bbox.max[1]),
-                acc[2].max(bbox.max[2])
-            )
-        }
-    );
-
-    return BBox::new(
-        min,
-        max
-    );
+    pub v: f32,
+    // index of the hit item in the scene's geometry list, primitives leave it at 0
+    // and the list or BVH that found the hit fills it in
+    pub object: usize
 }
 
 pub fn hit_list<'world>(items: &'world [Geometry], r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'world>> {
     let mut hit_anything: Option<HitRecord> = None;
     let mut closest_so_far = t_max;
-    items.iter().for_each(|item| {
+    items.iter().enumerate().for_each(|(i, item)| {
         if let Some(hit) = item.hit(r, t_min, closest_so_far) {
             closest_so_far = hit.t;
-            hit_anything = Some(hit);
+            hit_anything = Some(HitRecord { object: i, ..hit });
         }
     });
 
     hit_anything
 }
+
+impl Hitable for Vec<Geometry<'_>> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        hit_list(self, r, t_min, t_max)
+    }
+}
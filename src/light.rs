@@ -0,0 +1,268 @@
+use cgmath::{ InnerSpace, Vector3, dot };
+
+use std::fmt;
+
+use crate::hitable::{ Geometry, HitRecord };
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::util;
+
+// A piece of emissive geometry that can be sampled on its own: a whole sphere,
+// or a single triangle of a mesh.
+enum Emitter {
+    Sphere { object: usize },
+    Triangle { object: usize, tri: usize }
+}
+
+pub struct LightSample {
+    pub point: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    // index of the sampled item in the scene's geometry list
+    pub object: usize,
+    // probability density of having picked the point, per unit area
+    pub pdf_area: f32
+}
+
+pub struct LightStats {
+    pub emitters: usize,
+    pub total_area: f32
+}
+
+impl fmt::Display for LightStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} emitters with a total area of {}", self.emitters, self.total_area)
+    }
+}
+
+// Every emissive item in the scene, sampled uniformly by area.
+pub struct Lights<'a> {
+    geometry: &'a [Geometry<'a>],
+    emitters: Vec<Emitter>,
+    // running total of the emitter areas, for picking one in proportion to its area
+    cdf: Vec<f32>,
+    total_area: f32
+}
+
+impl<'a> Lights<'a> {
+    pub fn new(geometry: &'a [Geometry<'a>]) -> Self {
+        let mut emitters = Vec::new();
+        let mut cdf = Vec::new();
+        let mut total_area = 0.0;
+        let mut add = |emitter: Emitter, area: f32| {
+            total_area += area;
+            emitters.push(emitter);
+            cdf.push(total_area);
+        };
+
+        for (object, item) in geometry.iter().enumerate() {
+            if !matches!(item.material(), Material::DiffuseLight(_)) {
+                continue;
+            }
+            match item {
+                Geometry::Sphere(s) => add(Emitter::Sphere { object }, sphere_area(s.radius)),
+                Geometry::MovingSphere(ms) => add(Emitter::Sphere { object }, sphere_area(ms.radius)),
+                Geometry::Mesh(m) => {
+                    for tri in 0..m.triangle_count() {
+                        let (v0, v1, v2) = m.triangle(tri);
+                        add(Emitter::Triangle { object, tri }, 0.5 * (v1 - v0).cross(v2 - v0).magnitude());
+                    }
+                },
+                // glowing volumes have no surface to sample
+                Geometry::ConstantMedium(_) => {}
+            }
+        }
+
+        Self {
+            geometry,
+            emitters,
+            cdf,
+            total_area
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total_area <= 0.0
+    }
+
+    pub fn stats(&self) -> LightStats {
+        LightStats {
+            emitters: self.emitters.len(),
+            total_area: self.total_area
+        }
+    }
+
+    // The density, over solid angle as seen from the ray's origin, with which
+    // `sample` would have picked the point `r` hit. Zero if it isn't a light.
+    pub fn pdf(&self, r: &Ray, hit: &HitRecord) -> f32 {
+        if self.is_empty() || !matches!(hit.material, Material::DiffuseLight(_)) {
+            return 0.0;
+        }
+        let length = r.direction.magnitude();
+        let distance = hit.t * length;
+        let cos_light = dot(hit.normal, r.direction / length).abs();
+        if cos_light <= 0.0 {
+            return 0.0;
+        }
+        distance * distance / (cos_light * self.total_area)
+    }
+
+    // Picks a point on the lights at the given time, or None if there are none.
+    // `choice` decides which light and `u` where on it.
+    pub fn sample(&self, time: f32, choice: f32, u: (f32, f32)) -> Option<LightSample> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let target = choice * self.total_area;
+        let index = self.cdf.partition_point(|&c| c <= target).min(self.emitters.len() - 1);
+        let (point, normal, object) = match self.emitters[index] {
+            Emitter::Sphere { object } => {
+                let (center, radius) = match &self.geometry[object] {
+                    Geometry::Sphere(s) => (s.center, s.radius),
+                    Geometry::MovingSphere(ms) => (ms.center(time), ms.radius),
+                    _ => unreachable!("sphere emitter refers to a mesh or medium")
+                };
+                let normal = util::sample_unit_vector(u);
+                (center + radius * normal, normal, object)
+            },
+            Emitter::Triangle { object, tri } => {
+                let (v0, v1, v2) = match &self.geometry[object] {
+                    Geometry::Mesh(m) => m.triangle(tri),
+                    _ => unreachable!("triangle emitter refers to a sphere or medium")
+                };
+                // uniformly distributed barycentric coordinates
+                let su = u.0.sqrt();
+                let b0 = 1.0 - su;
+                let b1 = u.1 * su;
+                let point = b0 * v0 + b1 * v1 + (1.0 - b0 - b1) * v2;
+                (point, (v1 - v0).cross(v2 - v0).normalize(), object)
+            }
+        };
+
+        Some(LightSample {
+            point,
+            normal,
+            object,
+            pdf_area: 1.0 / self.total_area
+        })
+    }
+}
+
+fn sphere_area(radius: f32) -> f32 {
+    4.0 * std::f32::consts::PI * radius * radius
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::hit_list;
+    use crate::rng::Pcg32;
+    use crate::texture::Texture;
+
+    use rand::Rng;
+    use std::f32::consts::PI;
+
+    const SAMPLES: usize = 200_000;
+
+    // Integrates `Lights::pdf` over every direction from `origin`, which gives
+    // the chance of `sample` picking a point that can be seen from there.
+    fn integrate_pdf(lights: &Lights, geometry: &[Geometry], origin: Vector3<f32>, rng: &mut Pcg32) -> f32 {
+        let mut sum = 0.0;
+        for _ in 0..SAMPLES {
+            // not normalized, which the pdf has to cope with like camera rays
+            let direction = 2.0 * util::sample_unit_vector((rng.gen(), rng.gen()));
+            let r = Ray::new(origin, direction, 0.0);
+            if let Some(hit) = hit_list(geometry, &r, 0.001, f32::MAX) {
+                sum += lights.pdf(&r, &hit);
+            }
+        }
+        sum * 4.0 * PI / SAMPLES as f32
+    }
+
+    // Samples points on the lights and checks that wherever the ray from
+    // `origin` towards one hits it, `pdf` gives the density the point was
+    // drawn with, converted to solid angle.
+    fn check_sampled_pdf(lights: &Lights, geometry: &[Geometry], origin: Vector3<f32>, rng: &mut Pcg32) {
+        let mut checked = 0;
+        for _ in 0..1000 {
+            let sample = lights.sample(0.0, rng.gen(), (rng.gen(), rng.gen())).unwrap();
+            let r = Ray::new(origin, sample.point - origin, 0.0);
+            let hit = hit_list(geometry, &r, 0.001, f32::MAX).unwrap();
+            let to_light = sample.point - origin;
+            let cos_light = dot(sample.normal, to_light.normalize()).abs();
+            // the far side of a sphere, or seen so nearly edge on that rounding dominates
+            if (hit.t - 1.0).abs() > 1e-3 || cos_light < 0.05 {
+                continue;
+            }
+            let expected = sample.pdf_area * to_light.magnitude2() / cos_light;
+            let pdf = lights.pdf(&r, &hit);
+            assert!((pdf - expected).abs() <= 1e-3 * expected, "pdf {} for a point sampled with {}", pdf, expected);
+            checked += 1;
+        }
+        assert!(checked > 100);
+    }
+
+    #[test]
+    fn triangle_pdf_matches_its_samples() {
+        let texture = Texture::constant(1.0, 1.0, 1.0);
+        let material = Material::diffuse_light(&texture);
+        let geometry = vec![Geometry::mesh(vec![-1.0, -1.0, 0.0, 2.0, -0.5, 0.0, 0.0, 1.5, 0.5], vec![0, 1, 2], &material)];
+        let lights = Lights::new(&geometry);
+        let mut rng = Pcg32::new(5, 0);
+        let origin = Vector3::new(0.2, 0.1, -1.0);
+
+        check_sampled_pdf(&lights, &geometry, origin, &mut rng);
+        // every point of a triangle can be seen from in front of it
+        let total = integrate_pdf(&lights, &geometry, origin, &mut rng);
+        assert!((total - 1.0).abs() < 0.02, "pdf integrates to {}", total);
+    }
+
+    #[test]
+    fn sphere_pdf_matches_its_samples() {
+        let texture = Texture::constant(1.0, 1.0, 1.0);
+        let material = Material::diffuse_light(&texture);
+        let geometry = vec![Geometry::sphere(Vector3::new(0.0, 0.0, 0.0), 1.0, &material)];
+        let lights = Lights::new(&geometry);
+        let mut rng = Pcg32::new(6, 0);
+        let origin = Vector3::new(0.0, 0.0, 3.0);
+
+        check_sampled_pdf(&lights, &geometry, origin, &mut rng);
+        // only the cap facing the origin can be seen, (1 - r / d) / 2 of the area
+        let total = integrate_pdf(&lights, &geometry, origin, &mut rng);
+        assert!((total - 1.0 / 3.0).abs() < 0.01, "pdf integrates to {}", total);
+    }
+
+    #[test]
+    fn picks_emitters_in_proportion_to_area() {
+        let texture = Texture::constant(1.0, 1.0, 1.0);
+        let material = Material::diffuse_light(&texture);
+        let dull = Material::lambertian(&texture);
+        let geometry = vec![
+            Geometry::sphere(Vector3::new(0.0, 0.0, 0.0), 0.5, &material),
+            Geometry::sphere(Vector3::new(5.0, 0.0, 0.0), 1.0, &dull),
+            // two triangles with areas 2 and 0.5
+            Geometry::mesh(vec![0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 2.0, 0.0, 1.0, 0.0, 0.0], vec![0, 1, 2, 0, 3, 2], &material)
+        ];
+        let lights = Lights::new(&geometry);
+        let areas = [PI, 0.0, 2.0 + 1.0];
+        let total_area: f32 = areas.iter().sum();
+
+        let stats = lights.stats();
+        assert_eq!(stats.emitters, 3);
+        assert!((stats.total_area - total_area).abs() < 1e-5);
+        // the cdf ends at the total area, so the chances of picking each emitter add up to 1
+        assert_eq!(*lights.cdf.last().unwrap(), lights.total_area);
+
+        let mut counts = [0; 3];
+        let mut rng = Pcg32::new(7, 0);
+        for _ in 0..SAMPLES {
+            let sample = lights.sample(0.0, rng.gen(), (rng.gen(), rng.gen())).unwrap();
+            assert_eq!(sample.pdf_area, 1.0 / lights.total_area);
+            counts[sample.object] += 1;
+        }
+        for (count, area) in counts.iter().zip(&areas) {
+            let fraction = *count as f32 / SAMPLES as f32;
+            assert!((fraction - area / total_area).abs() < 0.005, "picked {} of the time instead of {}", fraction, area / total_area);
+        }
+    }
+}
@@ -1,21 +1,38 @@
 use minifb::{ Key, WindowOptions, Window };
-use clap::{ Arg, App, SubCommand };
+use clap::App;
 use cgmath::{ Vector3 };
 use rand::prelude::*;
-use std::env;
+use typed_arena::Arena;
+use std::path::Path;
 
+mod aov;
 mod bbox;
-// mod bvh;
+mod bvh;
 mod camera;
+mod constant_medium;
+mod denoise;
+mod exr;
+mod filter;
+mod framebuffer;
 mod hitable;
+mod light;
 mod material;
 mod mesh;
+mod mtl;
+mod obj;
+mod output;
 mod moving_sphere;
 mod perlin;
 mod ray;
 mod renderer;
+mod rng;
+mod sampler;
+mod scene;
+mod spectrum;
 mod sphere;
 mod texture;
+mod tile;
+mod tonemap;
 mod world;
 mod util;
 
@@ -29,6 +46,34 @@ fn main() {
                          -h, --height=[HEIGHT] 'Height of output image, in pixels'
                          -s, --samples=[NUM_SAMPLES] 'Number of samples per pixel'
                          -d, --depth=[MAX_DEPTH] 'Maximum number of ray bounces'
+                         --noise-threshold=[THRESHOLD] 'Stop sampling pixels once their noise is below this fraction of their brightness, e.g. 0.01'
+                         --min-samples=[NUM_SAMPLES] 'Samples every pixel gets before adaptive sampling may stop it'
+                         --sample-heatmap=[FILE] 'Save an image of how many samples each pixel took'
+                         --rr-depth=[DEPTH] 'Bounces before Russian roulette may end a path'
+                         --filter=[FILTER] 'Pixel filter: box (default), tent, gaussian, mitchell or lanczos'
+                         --filter-radius=[PIXELS] 'How far the pixel filter reaches, defaults to 0.5 for box, 1 for tent, 1.5 for gaussian, 2 for mitchell and 3 for lanczos'
+                         --sampler=[SAMPLER] 'How sample positions are picked: sobol (default), halton, stratified or random'
+                         --seed=[SEED] 'Seed for the random numbers, the same seed always renders the same image'
+                         --spectral 'Trace wavelengths instead of RGB, for dispersion in glass with Cauchy or Sellmeier coefficients'
+                         --no-accel 'Test every object for each ray instead of using a BVH'
+                         --bvh=[SPLIT] 'BVH split method: sah (default) or median'
+                         --mis=[HEURISTIC] 'Weighting of light and BSDF samples: power (default) or balance'
+                         --scene=[FILE] 'Scene description file to render instead of the built-in Cornell box'
+                         --obj=[FILE] 'Wavefront OBJ model to add to the scene'
+                         -o, --output=[FILE] 'Save the render to a .png, .jpg, .bmp, .exr, .hdr, .pfm or .ppm file'
+                         --exr-type=[TYPE] 'Pixel type for EXR output: half (default) or float'
+                         --ppm-ascii 'Write PPM output as plain text (P3) instead of binary (P6)'
+                         --tonemap=[OPERATOR] 'Tone mapping for 8-bit output and the preview: clamp (default), reinhard, extended-reinhard, hable or aces'
+                         --exposure=[EV] 'Exposure adjustment in stops before tone mapping'
+                         --white=[LUMINANCE] 'Luminance that maps to white with extended-reinhard or hable'
+                         --aovs=[PASSES] 'Comma separated passes to save with the output: albedo, normal, depth, position, object, material or all'
+                         --denoise 'Also save a denoised copy of the output, named like an AOV pass, and show it when done'
+                         --denoise-strength=[STRENGTH] 'How aggressively to denoise, 1 by default'
+                         --tile-size=[PIXELS] 'Width and height of the tiles the image is rendered in'
+                         --tile-order=[ORDER] 'Order tiles are rendered in: spiral (default), hilbert or scanline'
+                         --show-tiles 'Outline the tiles being rendered in the preview window, over the image as of the last finished pass'
+                         --tile-times=[FILE] 'Save the time spent on every tile as CSV'
+                         --headless 'Render without opening a preview window'
                         "
                     )
                     .get_matches();
@@ -37,6 +82,15 @@ fn main() {
     let mut height: usize = 320;
     let mut num_samples: i32 = 256;
     let mut max_depth: i32 = 128;
+    let mut rr_depth: i32 = 5;
+    let mut noise_threshold: Option<f32> = None;
+    let mut min_samples: i32 = 16;
+    let mut split = bvh::SplitMethod::Sah;
+    let mut heuristic = renderer::MisHeuristic::Power;
+    let mut tile_size: usize = 32;
+    let mut tile_order = tile::TileOrder::Spiral;
+    let mut sampler = sampler::SamplerKind::Sobol;
+    let mut filter_kind = filter::FilterKind::Box;
 
     if let Some(width_val) = matches.value_of("width") {
         match width_val.parse::<usize>() {
@@ -62,53 +116,319 @@ fn main() {
             Err(e) => panic!("Invalid max depth argument: {}", e)
         }
     }
+    if let Some(threshold_val) = matches.value_of("noise-threshold") {
+        match threshold_val.parse::<f32>() {
+            Ok(t) => noise_threshold = Some(t),
+            Err(e) => panic!("Invalid noise threshold argument: {}", e)
+        }
+    }
+    if let Some(min_samples_val) = matches.value_of("min-samples") {
+        match min_samples_val.parse::<i32>() {
+            Ok(s) => min_samples = s,
+            Err(e) => panic!("Invalid minimum samples argument: {}", e)
+        }
+    }
+    if let Some(rr_depth_val) = matches.value_of("rr-depth") {
+        match rr_depth_val.parse::<i32>() {
+            Ok(d) => rr_depth = d,
+            Err(e) => panic!("Invalid Russian roulette depth argument: {}", e)
+        }
+    }
+    if let Some(sampler_val) = matches.value_of("sampler") {
+        match sampler_val.parse::<sampler::SamplerKind>() {
+            Ok(s) => sampler = s,
+            Err(e) => panic!("Invalid sampler argument: {}", e)
+        }
+    }
+    if let Some(filter_val) = matches.value_of("filter") {
+        match filter_val.parse::<filter::FilterKind>() {
+            Ok(f) => filter_kind = f,
+            Err(e) => panic!("Invalid filter argument: {}", e)
+        }
+    }
+    let mut filter_radius = filter_kind.default_radius();
+    if let Some(radius_val) = matches.value_of("filter-radius") {
+        match radius_val.parse::<f32>() {
+            Ok(r) if r >= filter::MIN_RADIUS => filter_radius = r,
+            Ok(_) => panic!("Invalid filter radius argument: must be at least {}", filter::MIN_RADIUS),
+            Err(e) => panic!("Invalid filter radius argument: {}", e)
+        }
+    }
+    // without a seed every run is different, but printing it lets one be repeated
+    let seed = match matches.value_of("seed") {
+        Some(seed_val) => match seed_val.parse::<u64>() {
+            Ok(s) => s,
+            Err(e) => panic!("Invalid seed argument: {}", e)
+        },
+        None => random::<u64>()
+    };
+    println!("Seed: {}", seed);
+    if let Some(split_val) = matches.value_of("bvh") {
+        match split_val.parse::<bvh::SplitMethod>() {
+            Ok(s) => split = s,
+            Err(e) => panic!("Invalid BVH argument: {}", e)
+        }
+    }
 
-    let aspect: f32 = width as f32 / height as f32;
+    if let Some(mis_val) = matches.value_of("mis") {
+        match mis_val.parse::<renderer::MisHeuristic>() {
+            Ok(h) => heuristic = h,
+            Err(e) => panic!("Invalid MIS argument: {}", e)
+        }
+    }
 
-    let mut window = Window::new(
-        "Raytracer - ESC to exit",
-         width,
-         height,
-         WindowOptions::default()
-    ).unwrap_or_else(|e| {
-        panic!("{}", e);
+    if let Some(tile_size_val) = matches.value_of("tile-size") {
+        match tile_size_val.parse::<usize>() {
+            Ok(t) if t > 0 => tile_size = t,
+            Ok(_) => panic!("Invalid tile size argument: must be at least 1"),
+            Err(e) => panic!("Invalid tile size argument: {}", e)
+        }
+    }
+    if let Some(tile_order_val) = matches.value_of("tile-order") {
+        match tile_order_val.parse::<tile::TileOrder>() {
+            Ok(o) => tile_order = o,
+            Err(e) => panic!("Invalid tile order argument: {}", e)
+        }
+    }
+    let show_tiles = matches.is_present("show-tiles");
+
+    let denoise = matches.is_present("denoise");
+    let mut denoise_strength: f32 = 1.0;
+    if let Some(strength_val) = matches.value_of("denoise-strength") {
+        match strength_val.parse::<f32>() {
+            Ok(s) if s > 0.0 => denoise_strength = s,
+            Ok(_) => panic!("Invalid denoise strength argument: must be greater than 0"),
+            Err(e) => panic!("Invalid denoise strength argument: {}", e)
+        }
+    }
+
+    let mut exr_type = exr::PixelType::Half;
+    if let Some(exr_type_val) = matches.value_of("exr-type") {
+        match exr_type_val.parse::<exr::PixelType>() {
+            Ok(t) => exr_type = t,
+            Err(e) => panic!("Invalid EXR type argument: {}", e)
+        }
+    }
+    let output = matches.value_of("output").map(|output_val| {
+        let path = Path::new(output_val);
+        match output::OutputFormat::from_path(path, exr_type, matches.is_present("ppm-ascii")) {
+            Ok(format) => (path, format),
+            Err(e) => panic!("Invalid output argument: {}", e)
+        }
+    });
+    let heatmap = matches.value_of("sample-heatmap").map(|heatmap_val| {
+        let path = Path::new(heatmap_val);
+        match output::OutputFormat::from_path(path, exr_type, matches.is_present("ppm-ascii")) {
+            Ok(format) => (path, format),
+            Err(e) => panic!("Invalid sample heatmap argument: {}", e)
+        }
     });
+    let mut aovs = Vec::new();
+    if let Some(aovs_val) = matches.value_of("aovs") {
+        match aov::parse_list(aovs_val) {
+            Ok(a) => aovs = a,
+            Err(e) => panic!("Invalid AOV argument: {}", e)
+        }
+        if output.is_none() {
+            println!("--aovs has no effect without --output");
+        }
+    }
+    let headless = matches.is_present("headless");
+    if headless && output.is_none() {
+        println!("Rendering headless without --output, the image will not be saved");
+    }
 
+    let aspect: f32 = width as f32 / height as f32;
+
+    let mut window = if headless {
+        None
+    } else {
+        Some(Window::new(
+            "Raytracer - ESC to cancel",
+             width,
+             height,
+             WindowOptions::default()
+        ).unwrap_or_else(|e| {
+            panic!("{}", e);
+        }))
+    };
+
+    // textures and materials from scene and model files live as long as the world
+    let textures = Arena::new();
+    let materials = Arena::new();
+
+    let (camera, mut world, scene_tone_map) = match matches.value_of("scene") {
+        Some(scene_path) => match scene::load(Path::new(scene_path), aspect, seed, &textures, &materials) {
+            Ok(scene) => (scene.camera, scene.world, scene.tone_map),
+            Err(e) => panic!("Failed to load scene {}: {}", scene_path, e)
+        },
+        None => {
+            let (camera, world) = default_scene(aspect, seed, &textures, &materials);
+            (camera, world, None)
+        }
+    };
 
+    // the command line overrides what the scene asks for
+    let mut tone_map = scene_tone_map.unwrap_or_default();
+    if let Some(operator_val) = matches.value_of("tonemap") {
+        match operator_val.parse::<tonemap::Operator>() {
+            Ok(o) => tone_map.operator = o,
+            Err(e) => panic!("Invalid tonemap argument: {}", e)
+        }
+    }
+    if let Some(exposure_val) = matches.value_of("exposure") {
+        match exposure_val.parse::<f32>() {
+            Ok(ev) => tone_map.exposure = ev,
+            Err(e) => panic!("Invalid exposure argument: {}", e)
+        }
+    }
+    if let Some(white_val) = matches.value_of("white") {
+        match white_val.parse::<f32>().map_err(|e| e.to_string()).and_then(tonemap::white_point) {
+            Ok(w) => tone_map.white = Some(w),
+            Err(e) => panic!("Invalid white argument: {}", e)
+        }
+    }
+
+    if let Some(obj_path) = matches.value_of("obj") {
+        let white_material = materials.alloc(material::Material::lambertian(textures.alloc(texture::Texture::constant(0.73, 0.73, 0.73))));
+        match obj::load_geometry(Path::new(obj_path), white_material, &textures, &materials) {
+            Ok(geometry) => world.extend(geometry),
+            Err(e) => panic!("Failed to load {}: {}", obj_path, e)
+        }
+    }
+
+    let settings = renderer::Settings {
+        width,
+        height,
+        num_samples,
+        max_depth,
+        rr_depth,
+        heuristic,
+        tile_size,
+        tile_order,
+        noise_threshold,
+        min_samples,
+        seed,
+        sampler,
+        filter: filter::Filter::new(filter_kind, filter_radius),
+        spectral: matches.is_present("spectral")
+    };
+    // show every pass in the window, and stop when it's closed or ESC is pressed
+    let show_progress = |progress: &renderer::Progress| match &mut window {
+        Some(window) => {
+            window.set_title(&format!(
+                "Raytracer - {}/{} samples per pixel, {:.1} seconds - ESC to cancel",
+                progress.samples,
+                num_samples,
+                progress.elapsed.as_secs_f32()
+            ));
+            let mut buffer = progress.framebuffer.to_argb(&tone_map);
+            if show_tiles {
+                progress.active.iter().for_each(|t| tile::outline(&mut buffer, width, t, 0xffff_a500));
+            }
+            window.update_with_buffer(&buffer).unwrap();
+            window.is_open() && !window.is_key_down(Key::Escape)
+        },
+        None => true
+    };
+
+    let render = if matches.is_present("no-accel") {
+        renderer::draw(camera, &world, &world, &settings, show_progress)
+    } else {
+        let bvh = bvh::SceneBvh::new(&world, 0.0, 1.0, split);
+        println!("BVH ({:?}): {}", split, bvh.stats());
+        renderer::draw(camera, &bvh, &world, &settings, show_progress)
+    };
+    println!("Lights: {}", render.lights);
+    println!("Paths: {}", render.stats);
+    println!("Tiles ({}x{}, {:?} order): {}", tile_size, tile_size, tile_order, render.tiles);
+    if let Some(path) = matches.value_of("tile-times") {
+        match render.tiles.save(Path::new(path)) {
+            Ok(()) => println!("Saved {}", path),
+            Err(e) => panic!("Failed to save {}: {}", path, e)
+        }
+    }
+
+    let denoised = if denoise {
+        let start = std::time::Instant::now();
+        let denoised = denoise::denoise(&render.framebuffer, &render.aovs, denoise_strength);
+        println!("Denoised in {:.2} seconds", start.elapsed().as_secs_f32());
+        Some(denoised)
+    } else {
+        None
+    };
+
+    if let Some((path, format)) = output {
+        match output::save_with_aovs(path, format, &render.framebuffer, &tone_map, &render.aovs, &aovs) {
+            Ok(paths) => paths.iter().for_each(|p| println!("Saved {}", p.display())),
+            Err(e) => panic!("Failed to save {}: {}", path.display(), e)
+        }
+        if let Some(denoised) = &denoised {
+            let denoised_path = output::pass_path(path, "denoised");
+            match output::save(&denoised_path, format, denoised, &tone_map) {
+                Ok(()) => println!("Saved {}", denoised_path.display()),
+                Err(e) => panic!("Failed to save {}: {}", denoised_path.display(), e)
+            }
+        }
+    }
+
+    if let Some((path, format)) = heatmap {
+        match output::save_heatmap(path, format, width, height, &render.sample_counts) {
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => panic!("Failed to save {}: {}", path.display(), e)
+        }
+    }
+
+    if let Some(window) = &mut window {
+        window.set_title(&format!("Raytracer - {} samples per pixel - ESC to exit", render.samples));
+        let buffer = denoised.as_ref().unwrap_or(&render.framebuffer).to_argb(&tone_map);
+        while window.is_open() && !window.is_key_down(Key::Escape) {
+            window.update_with_buffer(&buffer).unwrap();
+        }
+    }
+}
+
+// The Cornell box scene used when no scene file is given.
+fn default_scene<'a>(
+    aspect: f32,
+    seed: u64,
+    textures: &'a Arena<texture::Texture<'a>>,
+    materials: &'a Arena<material::Material<'a>>) -> (camera::Camera, Vec<hitable::Geometry<'a>>) {
     let look_from = Vector3::new(278.0, 278.0, -800.0);
     let look_at = Vector3::new(278.0, 278.0, 0.0);
     let dist_to_focus = 10.0; // (Vector3::new(look_from.x, look_from.y, look_from.z) - look_at).magnitude();
     let aperture = 0.0;
     let camera = camera::Camera::new(look_from, look_at, Vector3::new(0.0, 1.0, 0.0), 40.0, aspect, aperture, dist_to_focus, 0.0, 1.0);
 
-    let green_texture = &texture::Texture::constant(0.12, 0.45, 0.15);
-    let red_texture = &texture::Texture::constant(0.65, 0.05, 0.05);
-    let white_texture = &texture::Texture::constant(0.73, 0.73, 0.73);
+    let green_texture = textures.alloc(texture::Texture::constant(0.12, 0.45, 0.15));
+    let red_texture = textures.alloc(texture::Texture::constant(0.65, 0.05, 0.05));
+    let white_texture = textures.alloc(texture::Texture::constant(0.73, 0.73, 0.73));
 
-    let green_material = &material::Material::lambertian(green_texture);
-    let red_material = &material::Material::lambertian(red_texture);
-    let white_material = &material::Material::lambertian(white_texture);
+    let green_material = materials.alloc(material::Material::lambertian(green_texture));
+    let red_material = materials.alloc(material::Material::lambertian(red_texture));
+    let white_material = materials.alloc(material::Material::lambertian(white_texture));
 
-    let metal_texture = &texture::Texture::constant(
-        0.5 * (1.0 + random::<f32>()),
-        0.5 * (1.0 + random::<f32>()),
-        0.5 * (1.0 + random::<f32>())
-    );
+    let mut rng = rng::Pcg32::new(seed, 0);
+    let metal_texture = textures.alloc(texture::Texture::constant(
+        0.5 * (1.0 + rng.gen::<f32>()),
+        0.5 * (1.0 + rng.gen::<f32>()),
+        0.5 * (1.0 + rng.gen::<f32>())
+    ));
 
-    let metal_material = &material::Material::metal(metal_texture, 0.5 * random::<f32>());
+    let metal_material = materials.alloc(material::Material::metal(metal_texture, 0.5 * rng.gen::<f32>()));
 
-    let emissive_texture = &texture::Texture::constant(15.0, 15.0, 15.0);
+    let emissive_texture = textures.alloc(texture::Texture::constant(15.0, 15.0, 15.0));
 
-    let dielectric_material = &material::Material::dielectric(1.5);
-    let emissive_material = &material::Material::diffuse_light(emissive_texture);
+    let dielectric_material = materials.alloc(material::Material::dielectric(1.5));
+    let emissive_material = materials.alloc(material::Material::diffuse_light(emissive_texture));
 
-    let img_texture = &texture::Texture::image("./img/2k_mars.jpg");
-    let mars_material = &material::Material::lambertian(img_texture);
+    let img_texture = textures.alloc(texture::Texture::image("./img/2k_mars.jpg", false));
+    let mars_material = materials.alloc(material::Material::lambertian(img_texture));
 
-    let noise_texture = &texture::Texture::noise(4.0);
-    let noise_material = &material::Material::lambertian(noise_texture);
+    let noise_texture = textures.alloc(texture::Texture::noise(4.0, &mut rng));
+    let noise_material = materials.alloc(material::Material::lambertian(noise_texture));
 
-    let mut world = world::cornell_box(
+    let world = world::cornell_box(
         green_material,
         red_material,
         white_material,
@@ -118,10 +438,6 @@ fn main() {
         noise_material,
         emissive_material
     );
-    // let bvh = bvh::BvhNode::new(&mut world[..], 0.0, 1.0);
-    let buffer = renderer::draw(camera, world, width, height, num_samples, max_depth);
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        window.update_with_buffer(&buffer).unwrap();
-    }
+    (camera, world)
 }
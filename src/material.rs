@@ -3,6 +3,8 @@ use crate::hitable::HitRecord;
 use crate::texture::{ Texture, Textured };
 use crate::util;
 
+use std::f32::consts;
+
 use cgmath::{
     Vector3,
     dot,
@@ -19,24 +21,88 @@ pub struct Metal<'texture> {
 }
 
 pub struct Dielectric {
-    pub ref_idx: f32
+    // the index of refraction used when rendering in RGB
+    pub ref_idx: f32,
+    pub dispersion: Dispersion
+}
+
+// How a dielectric's index of refraction changes with the wavelength, which
+// splits white light into colors when rendering spectrally.
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    // the same index for every wavelength
+    None,
+    // n = a + b / λ², with λ in micrometres
+    Cauchy { a: f32, b: f32 },
+    // n² = 1 + Σ b λ² / (λ² - c), with λ in micrometres
+    Sellmeier { b: [f32; 3], c: [f32; 3] }
+}
+
+impl Dispersion {
+    // The index of refraction at a wavelength in nanometres.
+    pub fn ref_idx(&self, lambda: f32) -> Option<f32> {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match self {
+            Dispersion::None => None,
+            Dispersion::Cauchy { a, b } => Some(a + b / l2),
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                Some((1.0 + sum).sqrt())
+            }
+        }
+    }
 }
 
+// The yellow helium line that glass catalogues quote a single index at.
+const D_LINE: f32 = 587.6;
+
 pub struct DiffuseLight<'texture> {
     pub emit: &'texture Texture<'texture>
 }
 
+// The inside of a volume, scattering light equally in every direction.
+pub struct Isotropic<'texture> {
+    pub albedo: &'texture Texture<'texture>
+}
+
 pub struct Scatter {
+    // the BSDF times the cosine over the pdf, what the light along `ray` is scaled by
     pub attenuation: Vector3<f32>,
-    pub ray: Ray
+    pub ray: Ray,
+    // density over solid angle of having picked `ray`, zero for specular lobes
+    pub pdf: f32,
+    // perfect reflection or refraction, which only this one direction contributes to
+    pub specular: bool
 }
 
 pub trait Scattered {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter>;
+    // Picks the direction the light continues in from uniform samples: `choice`
+    // for choosing between lobes and `u` for the direction within one.
+    // `wavelength`, in nanometres, is given when rendering spectrally.
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, wavelength: Option<f32>, choice: f32, u: (f32, f32)) -> Option<Scatter>;
+
+    // The BSDF for light arriving from `direction` and leaving back along
+    // `r_in`. Materials that only scatter into single directions can't be
+    // evaluated like this and return black.
+    fn bsdf(&self, _r_in: &Ray, _hit: &HitRecord, _direction: Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(0.0, 0.0, 0.0)
+    }
+
+    // The density, over solid angle, with which `scatter` picks `direction`.
+    // Zero for perfect reflection and refraction, which have no density.
+    fn pdf(&self, _r_in: &Ray, _hit: &HitRecord, _direction: Vector3<f32>) -> f32 {
+        0.0
+    }
+
+    // True when the material only reflects or refracts into single directions,
+    // so sampling the lights from it would never find anything.
+    fn is_specular(&self) -> bool {
+        false
+    }
 }
 
 pub trait Emitter {
-    fn emitted(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32> {
+    fn emitted(&self, _u: f32, _v: f32, _p: &Vector3<f32>) -> Vector3<f32> {
         Vector3::new(0.0, 0.0, 0.0)
     }
 }
@@ -45,7 +111,8 @@ pub enum Material<'texture> {
     Lambertian(Lambertian<'texture>),
     Metal(Metal<'texture>),
     Dielectric(Dielectric),
-    DiffuseLight(DiffuseLight<'texture>)
+    DiffuseLight(DiffuseLight<'texture>),
+    Isotropic(Isotropic<'texture>)
 }
 
 impl<'texture> Material<'texture> {
@@ -58,21 +125,93 @@ impl<'texture> Material<'texture> {
     }
 
     pub fn dielectric(ref_idx: f32) -> Material<'texture> {
-        Material::Dielectric(Dielectric { ref_idx })
+        Material::Dielectric(Dielectric { ref_idx, dispersion: Dispersion::None })
+    }
+
+    // A dielectric whose index depends on the wavelength. RGB renders use the
+    // index at the d line.
+    pub fn dispersive_dielectric(dispersion: Dispersion) -> Material<'texture> {
+        let ref_idx = dispersion.ref_idx(D_LINE).unwrap_or(1.0);
+        Material::Dielectric(Dielectric { ref_idx, dispersion })
     }
 
     pub fn diffuse_light(emit: &'texture Texture) -> Material<'texture> {
         Material::DiffuseLight(DiffuseLight { emit })
     }
+
+    pub fn isotropic(albedo: &'texture Texture) -> Material<'texture> {
+        Material::Isotropic(Isotropic { albedo })
+    }
+
+    // The unlit surface color at a hit, for the albedo output pass.
+    pub fn albedo(&self, hit: &HitRecord) -> Vector3<f32> {
+        match self {
+            Material::Lambertian(l) => l.albedo.value(hit.u, hit.v, &hit.p),
+            Material::Metal(m) => m.albedo.value(hit.u, hit.v, &hit.p),
+            Material::Dielectric(_) => Vector3::new(1.0, 1.0, 1.0),
+            // lights keep the hue of their emission, scaled down into [0, 1]
+            Material::DiffuseLight(dl) => {
+                let emit = dl.emit.value(hit.u, hit.v, &hit.p);
+                emit / emit.x.max(emit.y).max(emit.z).max(1.0)
+            },
+            Material::Isotropic(i) => i.albedo.value(hit.u, hit.v, &hit.p)
+        }
+    }
+
+    // True for the inside of a volume, which has no surface for light to
+    // arrive at an angle to.
+    pub fn is_volume(&self) -> bool {
+        matches!(self, Material::Isotropic(_))
+    }
+
+    // True when the direction `scatter` picks depends on the wavelength, so a
+    // path can only carry on with the one it was picked for.
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Material::Dielectric(d) => !matches!(d.dispersion, Dispersion::None),
+            _ => false
+        }
+    }
 }
 
 impl Scattered for Material<'_> {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, wavelength: Option<f32>, choice: f32, u: (f32, f32)) -> Option<Scatter> {
         match &hit.material {
-            Material::Lambertian(l) => l.scatter(r_in, &hit),
-            Material::Metal(m) => m.scatter(r_in, &hit),
-            Material::Dielectric(d) => d.scatter(r_in, &hit),
-            Material::DiffuseLight(dl) => dl.scatter(r_in, &hit)
+            Material::Lambertian(l) => l.scatter(r_in, hit, wavelength, choice, u),
+            Material::Metal(m) => m.scatter(r_in, hit, wavelength, choice, u),
+            Material::Dielectric(d) => d.scatter(r_in, hit, wavelength, choice, u),
+            Material::DiffuseLight(dl) => dl.scatter(r_in, hit, wavelength, choice, u),
+            Material::Isotropic(i) => i.scatter(r_in, hit, wavelength, choice, u)
+        }
+    }
+
+    fn bsdf(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Vector3<f32> {
+        match &hit.material {
+            Material::Lambertian(l) => l.bsdf(r_in, hit, direction),
+            Material::Metal(m) => m.bsdf(r_in, hit, direction),
+            Material::Dielectric(d) => d.bsdf(r_in, hit, direction),
+            Material::DiffuseLight(dl) => dl.bsdf(r_in, hit, direction),
+            Material::Isotropic(i) => i.bsdf(r_in, hit, direction)
+        }
+    }
+
+    fn pdf(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> f32 {
+        match &hit.material {
+            Material::Lambertian(l) => l.pdf(r_in, hit, direction),
+            Material::Metal(m) => m.pdf(r_in, hit, direction),
+            Material::Dielectric(d) => d.pdf(r_in, hit, direction),
+            Material::DiffuseLight(dl) => dl.pdf(r_in, hit, direction),
+            Material::Isotropic(i) => i.pdf(r_in, hit, direction)
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        match &self {
+            Material::Lambertian(l) => l.is_specular(),
+            Material::Metal(m) => m.is_specular(),
+            Material::Dielectric(d) => d.is_specular(),
+            Material::DiffuseLight(dl) => dl.is_specular(),
+            Material::Isotropic(i) => i.is_specular()
         }
     }
 }
@@ -83,57 +222,112 @@ impl Emitter for Material<'_> {
             Material::Lambertian(l) => l.emitted(u, v, p),
             Material::Metal(m) => m.emitted(u, v, p),
             Material::Dielectric(d) => d.emitted(u, v, p),
-            Material::DiffuseLight(dl) => dl.emitted(u, v, p)
+            Material::DiffuseLight(dl) => dl.emitted(u, v, p),
+            Material::Isotropic(i) => i.emitted(u, v, p)
         }
     }
 }
 
 impl Scattered for Lambertian<'_> {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter> {
-        let target = hit.p + hit.normal + util::random_in_unit_sphere();
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, _wavelength: Option<f32>, _choice: f32, u: (f32, f32)) -> Option<Scatter> {
+        // the cosine cancels out against the pdf, leaving just the albedo
+        let direction = util::Onb::from_w(facing_normal(&r_in, hit)).local(util::sample_cosine_direction(u));
         Some(Scatter {
             attenuation: self.albedo.value(hit.u, hit.v, &hit.p),
-            ray: Ray::new(hit.p, target - hit.p, r_in.time)
+            ray: Ray::new(hit.p, direction, r_in.time),
+            pdf: self.pdf(&r_in, hit, direction),
+            specular: false
         })
     }
+
+    fn bsdf(&self, _r_in: &Ray, hit: &HitRecord, _direction: Vector3<f32>) -> Vector3<f32> {
+        self.albedo.value(hit.u, hit.v, &hit.p) / consts::PI
+    }
+
+    fn pdf(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> f32 {
+        dot(facing_normal(r_in, hit), direction.normalize()).max(0.0) / consts::PI
+    }
 }
 
 impl Emitter for Lambertian<'_> {}
 
+impl Metal<'_> {
+    // Fuzz widens a Phong lobe around the mirror direction. The exponent is the
+    // inverse of the mapping from MTL shininess, so fuzz 1 is a uniform lobe
+    // and fuzz 0 a perfect mirror.
+    fn exponent(&self) -> f32 {
+        2.0 / (self.fuzz * self.fuzz) - 2.0
+    }
+
+    fn lobe(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> f32 {
+        let reflected = reflect(r_in.direction.normalize(), hit.normal);
+        let cos_alpha = dot(reflected, direction.normalize()).max(0.0);
+        (self.exponent() + 1.0) / (2.0 * consts::PI) * cos_alpha.powf(self.exponent())
+    }
+}
+
 impl Scattered for Metal<'_> {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, _wavelength: Option<f32>, _choice: f32, u: (f32, f32)) -> Option<Scatter> {
         let reflected = reflect(r_in.direction.normalize(), hit.normal);
-        if dot(reflected, hit.normal) > 0.0 {
+        let direction = if self.is_specular() {
+            reflected
+        } else {
+            util::Onb::from_w(reflected).local(util::sample_phong_direction(self.exponent(), u))
+        };
+
+        // directions that end up below the surface are absorbed
+        if dot(direction, facing_normal(&r_in, hit)) > 0.0 {
             Some(Scatter {
                 attenuation: self.albedo.value(hit.u, hit.v, &hit.p),
-                ray: Ray::new(
-                    hit.p,
-                    reflected + self.fuzz * util::random_in_unit_sphere(),
-                    r_in.time
-                )
+                ray: Ray::new(hit.p, direction, r_in.time),
+                pdf: self.pdf(&r_in, hit, direction),
+                specular: self.is_specular()
             })
         } else {
             None
         }
     }
+
+    // Chosen so that sampling the lobe weighs every direction by the albedo,
+    // like a mirror does.
+    fn bsdf(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Vector3<f32> {
+        let cos_theta = dot(facing_normal(r_in, hit), direction.normalize());
+        if self.is_specular() || cos_theta <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+        self.albedo.value(hit.u, hit.v, &hit.p) * self.lobe(r_in, hit, direction) / cos_theta
+    }
+
+    fn pdf(&self, r_in: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> f32 {
+        if self.is_specular() {
+            0.0
+        } else {
+            self.lobe(r_in, hit, direction)
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        self.fuzz <= 0.0
+    }
 }
 
 impl Emitter for Metal<'_> {}
 
 impl Scattered for Dielectric {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, wavelength: Option<f32>, choice: f32, _u: (f32, f32)) -> Option<Scatter> {
         let dot_prod = dot(r_in.direction, hit.normal);
+        let ref_idx = wavelength.and_then(|lambda| self.dispersion.ref_idx(lambda)).unwrap_or(self.ref_idx);
 
         let (outward_normal, ni_over_nt, cosine) = if dot_prod > 0.0 {
             (
                 -hit.normal,
-                self.ref_idx,
-                self.ref_idx * dot_prod / r_in.direction.magnitude()
+                ref_idx,
+                ref_idx * dot_prod / r_in.direction.magnitude()
             )
         } else {
             (
                 hit.normal,
-                1.0 / self.ref_idx,
+                1.0 / ref_idx,
                 -dot_prod / r_in.direction.magnitude()
             )
         };
@@ -142,8 +336,8 @@ impl Scattered for Dielectric {
         let reflected = reflect(r_in.direction, hit.normal);
 
         if let Some(refracted) = refract(r_in.direction, outward_normal, ni_over_nt) {
-            let reflection_prob = schlick(cosine, self.ref_idx);
-            let out_dir = if rand::random::<f32>() < reflection_prob {
+            let reflection_prob = schlick(cosine, ref_idx);
+            let out_dir = if choice < reflection_prob {
                 reflected
             } else {
                 refracted
@@ -155,7 +349,9 @@ impl Scattered for Dielectric {
                     hit.p,
                     out_dir,
                     r_in.time
-                )
+                ),
+                pdf: 0.0,
+                specular: true
             })
         } else {
             Some(Scatter {
@@ -164,14 +360,29 @@ impl Scattered for Dielectric {
                     hit.p,
                     reflected,
                     r_in.time
-                )
+                ),
+                pdf: 0.0,
+                specular: true
             })
         }
     }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 impl Emitter for Dielectric {}
 
+// The normal on the side of the surface the ray arrived from.
+pub fn facing_normal(r_in: &Ray, hit: &HitRecord) -> Vector3<f32> {
+    if dot(r_in.direction, hit.normal) > 0.0 {
+        -hit.normal
+    } else {
+        hit.normal
+    }
+}
+
 pub fn reflect(v: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
     v - 2.0 * dot(v, n) * n
 }
@@ -194,13 +405,84 @@ pub fn schlick(cosine: f32, ref_idx: f32) -> f32 {
 }
 
 impl Scattered for DiffuseLight<'_> {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, _r_in: Ray, _hit: &HitRecord, _wavelength: Option<f32>, _choice: f32, _u: (f32, f32)) -> Option<Scatter> {
         None
     }
+
+    // lights don't reflect anything
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 impl Emitter for DiffuseLight<'_> {
     fn emitted(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32> {
         self.emit.value(u, v, p)
     }
-}
\ No newline at end of file
+}
+
+impl Scattered for Isotropic<'_> {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord, _wavelength: Option<f32>, _choice: f32, u: (f32, f32)) -> Option<Scatter> {
+        // the phase function cancels out against the pdf, leaving the albedo
+        let direction = util::sample_unit_vector(u);
+        Some(Scatter {
+            attenuation: self.albedo.value(hit.u, hit.v, &hit.p),
+            ray: Ray::new(hit.p, direction, r_in.time),
+            pdf: self.pdf(&r_in, hit, direction),
+            specular: false
+        })
+    }
+
+    fn bsdf(&self, _r_in: &Ray, hit: &HitRecord, _direction: Vector3<f32>) -> Vector3<f32> {
+        self.albedo.value(hit.u, hit.v, &hit.p) / (4.0 * consts::PI)
+    }
+
+    fn pdf(&self, _r_in: &Ray, _hit: &HitRecord, _direction: Vector3<f32>) -> f32 {
+        1.0 / (4.0 * consts::PI)
+    }
+}
+
+impl Emitter for Isotropic<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+
+    use rand::Rng;
+
+    fn random_direction(rng: &mut Pcg32) -> Vector3<f32> {
+        util::sample_unit_vector((rng.gen(), rng.gen()))
+    }
+
+    #[test]
+    fn lambertian_reports_the_pdf_it_samples_with() {
+        let texture = Texture::constant(0.2, 0.4, 0.6);
+        let material = Material::lambertian(&texture);
+        let mut rng = Pcg32::new(8, 0);
+
+        for _ in 0..1000 {
+            let hit = HitRecord {
+                t: 1.0,
+                p: Vector3::new(0.0, 0.0, 0.0),
+                normal: random_direction(&mut rng),
+                material: &material,
+                u: 0.0,
+                v: 0.0,
+                object: 0
+            };
+            // from either side of the surface, and not normalized
+            let r_in = Ray::new(Vector3::new(1.0, 2.0, 3.0), 3.0 * random_direction(&mut rng), 0.0);
+            let normal = facing_normal(&r_in, &hit);
+
+            let scatter = material.scatter(r_in, &hit, None, rng.gen(), (rng.gen(), rng.gen())).unwrap();
+            let direction = scatter.ray.direction;
+            let cos_theta = dot(normal, direction.normalize());
+            assert!(cos_theta >= -1e-6, "scattered into the surface, cos {}", cos_theta);
+            assert!((scatter.pdf - cos_theta.max(0.0) / consts::PI).abs() < 1e-6);
+            assert_eq!(scatter.pdf, material.pdf(&r_in, &hit, direction));
+            assert!(!scatter.specular);
+            assert_eq!(scatter.attenuation, Vector3::new(0.2, 0.4, 0.6));
+        }
+    }
+}
@@ -1,8 +1,8 @@
-use std::f32::{ MIN, MAX };
 use crate::ray::Ray;
 use crate::material::Material;
 use crate::hitable::{ Hitable, HitRecord };
 use crate::bbox::{ Bounded, BBox };
+use crate::bvh::{ Bvh, SplitMethod };
 
 use cgmath::{
     dot,
@@ -15,19 +15,59 @@ const EPSILON: f32 = 0.0000001;
 pub struct Mesh<'material> {
     pub vertices: Vec<f32>,
     pub indices: Vec<usize>,
-    pub material: &'material Material<'material>
+    pub material: &'material Material<'material>,
+    // optional per-vertex attributes, indexed like `vertices`
+    pub normals: Option<Vec<f32>>,
+    pub uvs: Option<Vec<f32>>,
+    // built over the triangles once, since the vertices never change afterwards
+    bvh: Bvh
 }
 
 impl<'material> Mesh<'material> {
     pub fn new(vertices: Vec<f32>, indices: Vec<usize>, material: &'material Material) -> Self {
+        assert_eq!(vertices.len() % 3, 0, "mesh needs 3 components per vertex");
+        let triangle_bounds: Vec<BBox> = (0..indices.len() / 3)
+            .map(|tri| {
+                let (v0, v1, v2) = triangle(&vertices, &indices, tri);
+                BBox::new(v0, v0).merge(&BBox::new(v1, v1)).merge(&BBox::new(v2, v2))
+            })
+            .collect();
+        let bvh = Bvh::new(&triangle_bounds, SplitMethod::Sah);
+
         Self {
             vertices,
             indices,
-            material
+            material,
+            normals: None,
+            uvs: None,
+            bvh
         }
     }
 
-    fn hit_triangle(&self, r: &Ray, t_min: f32, t_max: f32, v0: Vector3<f32>, v1: Vector3<f32>, v2: Vector3<f32>) -> Option<HitRecord> {
+    // 3 components per vertex, interpolated across each triangle for shading
+    pub fn with_normals(mut self, normals: Vec<f32>) -> Self {
+        assert_eq!(normals.len(), self.vertices.len(), "mesh needs one normal per vertex");
+        self.normals = Some(normals);
+        self
+    }
+
+    // 2 components per vertex, replacing the barycentric coordinates as texture coordinates
+    pub fn with_uvs(mut self, uvs: Vec<f32>) -> Self {
+        assert_eq!(uvs.len(), self.vertices.len() / 3 * 2, "mesh needs one texture coordinate per vertex");
+        self.uvs = Some(uvs);
+        self
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn triangle(&self, tri: usize) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        triangle(&self.vertices, &self.indices, tri)
+    }
+
+    fn hit_triangle(&self, r: &Ray, t_min: f32, t_max: f32, tri: usize) -> Option<HitRecord<'_>> {
+        let (v0, v1, v2) = triangle(&self.vertices, &self.indices, tri);
         let edge1 = v1 - v0;
         let edge2 = v2 - v0;
 
@@ -44,7 +84,7 @@ impl<'material> Mesh<'material> {
         let s = r.origin - v0;
         let u = f * dot(s, h);
 
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return None;
         }
 
@@ -58,19 +98,37 @@ impl<'material> Mesh<'material> {
         // compute intersection point
         let t = f * dot(edge2, q);
         if t > t_min && t < t_max {
-            let n = edge1.cross(edge2);
+            let w = 1.0 - u - v;
+            let corners = [self.indices[3 * tri], self.indices[3 * tri + 1], self.indices[3 * tri + 2]];
+            let geometric = edge1.cross(edge2);
+            let n = match &self.normals {
+                Some(normals) => {
+                    let n = w * vertex(normals, corners[0]) + u * vertex(normals, corners[1]) + v * vertex(normals, corners[2]);
+                    // zero normals, or opposing ones that cancel out, can't be normalized
+                    if n.magnitude2() > 1e-12 { n } else { geometric }
+                },
+                None => geometric
+            };
+            let (tex_u, tex_v) = match &self.uvs {
+                Some(uvs) => (
+                    w * uvs[2 * corners[0]] + u * uvs[2 * corners[1]] + v * uvs[2 * corners[2]],
+                    w * uvs[2 * corners[0] + 1] + u * uvs[2 * corners[1] + 1] + v * uvs[2 * corners[2] + 1]
+                ),
+                None => (u, v)
+            };
             let p = r.point_at_parameter(t);
-            return Some(HitRecord {
-                t: t,
-                p: p,
+            Some(HitRecord {
+                t,
+                p,
                 normal: n.normalize(),
-                material: &self.material,
-                u: u,
-                v: v
-            });
+                material: self.material,
+                u: tex_u,
+                v: tex_v,
+                object: 0
+            })
         } else {
             // line intersection but not ray intersection
-            return None;
+            None
         }
     }
 }
@@ -78,7 +136,7 @@ impl<'material> Mesh<'material> {
 impl Bounded for Mesh<'_> {
     fn bounds(&self, _t0: f32, _t1: f32) -> BBox {
         // TODO: cleaner way?
-        let (mut x_min, mut x_max, mut y_min, mut y_max, mut z_min, mut z_max) = (MAX, MIN, MAX, MIN, MAX, MIN);
+        let (mut x_min, mut x_max, mut y_min, mut y_max, mut z_min, mut z_max) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN, f32::MAX, f32::MIN);
         self.vertices.chunks(3)
             .for_each(|v| {
                 x_min = x_min.min(v[0]);
@@ -95,7 +153,7 @@ impl Bounded for Mesh<'_> {
                 z_min - 0.1
             ),
             Vector3::new(
-                x_max,
+                x_max + 0.1,
                 y_max + 0.1,
                 z_max + 0.1
             )
@@ -104,19 +162,110 @@ impl Bounded for Mesh<'_> {
 }
 
 impl Hitable for Mesh<'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut hit_anything: Option<HitRecord> = None;
-        let mut closest_so_far = t_max;
-        self.indices.chunks(3).for_each(|tri| {
-            let v0 = Vector3::new(self.vertices[3 * tri[0]], self.vertices[3 * tri[0] + 1], self.vertices[3 * tri[0] + 2]);
-            let v1 = Vector3::new(self.vertices[3 * tri[1]], self.vertices[3 * tri[1] + 1], self.vertices[3 * tri[1] + 2]);
-            let v2 = Vector3::new(self.vertices[3 * tri[2]], self.vertices[3 * tri[2] + 1], self.vertices[3 * tri[2] + 2]);
-            if let Some(hit) = self.hit_triangle(r, t_min, closest_so_far, v0, v1, v2) {
-                closest_so_far = hit.t;
-                hit_anything = Some(hit);
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.bvh.traverse(r, t_min, t_max, |tri, t_min, t_max| self.hit_triangle(r, t_min, t_max, tri))
+    }
+}
+
+fn vertex(vertices: &[f32], index: usize) -> Vector3<f32> {
+    Vector3::new(vertices[3 * index], vertices[3 * index + 1], vertices[3 * index + 2])
+}
+
+fn triangle(vertices: &[f32], indices: &[usize], tri: usize) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+    (
+        vertex(vertices, indices[3 * tri]),
+        vertex(vertices, indices[3 * tri + 1]),
+        vertex(vertices, indices[3 * tri + 2])
+    )
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+    use crate::texture::Texture;
+
+    use rand::Rng;
+
+    // a bumpy, jittered grid with some stray triangles of all sizes through it
+    fn irregular_mesh(rng: &mut Pcg32) -> (Vec<f32>, Vec<usize>) {
+        let n = 12;
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for j in 0..n {
+            for i in 0..n {
+                vertices.push(i as f32 + rng.gen_range(-0.3, 0.3));
+                vertices.push(rng.gen_range(-1.0, 1.0));
+                vertices.push(j as f32 + rng.gen_range(-0.3, 0.3));
+            }
+        }
+        for j in 0..n - 1 {
+            for i in 0..n - 1 {
+                let corner = j * n + i;
+                indices.extend_from_slice(&[corner, corner + 1, corner + n, corner + 1, corner + n + 1, corner + n]);
+            }
+        }
+        for _ in 0..40 {
+            let first = vertices.len() / 3;
+            let center = [rng.gen_range(0.0, n as f32), rng.gen_range(-2.0, 2.0), rng.gen_range(0.0, n as f32)];
+            let size = rng.gen_range(0.05, 4.0);
+            for _ in 0..3 {
+                for c in &center {
+                    vertices.push(c + size * rng.gen_range(-1.0, 1.0));
+                }
+            }
+            indices.extend_from_slice(&[first, first + 1, first + 2]);
+        }
+        (vertices, indices)
+    }
+
+    #[test]
+    #[should_panic(expected = "mesh needs 3 components per vertex")]
+    fn rejects_truncated_vertices() {
+        let texture = Texture::constant(0.5, 0.5, 0.5);
+        let material = Material::lambertian(&texture);
+        Mesh::new(vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0], vec![0, 1, 2], &material);
+    }
+
+    #[test]
+    #[should_panic(expected = "mesh needs one texture coordinate per vertex")]
+    fn rejects_odd_length_uvs() {
+        let texture = Texture::constant(0.5, 0.5, 0.5);
+        let material = Material::lambertian(&texture);
+        Mesh::new(vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0], vec![0, 1, 2], &material)
+            .with_uvs(vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn finds_the_same_hit_as_every_triangle() {
+        let texture = Texture::constant(0.5, 0.5, 0.5);
+        let material = Material::lambertian(&texture);
+        let mut rng = Pcg32::new(4, 0);
+        let (vertices, indices) = irregular_mesh(&mut rng);
+        let mesh = Mesh::new(vertices, indices, &material);
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = Vector3::new(rng.gen_range(-5.0, 17.0), rng.gen_range(-6.0, 6.0), rng.gen_range(-5.0, 17.0));
+            let target = Vector3::new(rng.gen_range(0.0, 11.0), rng.gen_range(-1.0, 1.0), rng.gen_range(0.0, 11.0));
+            let r = Ray::new(origin, target - origin, 0.0);
+
+            let mut expected: Option<HitRecord> = None;
+            for tri in 0..mesh.triangle_count() {
+                let t_max = expected.as_ref().map_or(f32::MAX, |hit| hit.t);
+                if let Some(hit) = mesh.hit_triangle(&r, 0.001, t_max, tri) {
+                    expected = Some(hit);
+                }
             }
-        });
 
-        hit_anything
+            match (expected, mesh.hit(&r, 0.001, f32::MAX)) {
+                (Some(e), Some(f)) => {
+                    assert_eq!((e.t, e.u, e.v), (f.t, f.u, f.v));
+                    hits += 1;
+                },
+                (None, None) => {},
+                (e, f) => panic!("every triangle hit {:?}, BVH hit {:?}", e.map(|h| h.t), f.map(|h| h.t))
+            }
+        }
+        assert!(hits > 1000, "only {} rays hit", hits);
     }
-}
\ No newline at end of file
+}
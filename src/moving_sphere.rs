@@ -51,7 +51,7 @@ impl Bounded for MovingSphere<'_> {
 }
 
 impl Hitable for MovingSphere<'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = r.origin - self.center(r.time);
         let a = dot(r.direction, r.direction);
         let b = dot(oc, r.direction);
@@ -68,9 +68,10 @@ impl Hitable for MovingSphere<'_> {
                     t: temp,
                     p: hit_point,
                     normal,
-                    material: &self.material,
+                    material: self.material,
                     u,
-                    v
+                    v,
+                    object: 0
                 });
             }
             temp = (-b + discriminant.sqrt()) / a;
@@ -82,9 +83,10 @@ impl Hitable for MovingSphere<'_> {
                     t: temp,
                     p: hit_point,
                     normal,
-                    material: &self.material,
+                    material: self.material,
                     u,
-                    v
+                    v,
+                    object: 0
                 });
             }
         }
@@ -0,0 +1,282 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{ BufRead, BufReader };
+use std::path::Path;
+
+use cgmath::Vector3;
+use typed_arena::Arena;
+
+use crate::material::Material;
+use crate::obj::ObjError;
+use crate::texture::Texture;
+
+// The statements of one `newmtl` block that have a counterpart in our materials.
+pub struct MtlMaterial {
+    pub name: String,
+    pub diffuse: Option<Vector3<f32>>,
+    pub diffuse_map: Option<String>,
+    pub specular: Option<Vector3<f32>>,
+    pub shininess: Option<f32>,
+    pub ior: Option<f32>,
+    pub dissolve: Option<f32>,
+    pub emission: Option<Vector3<f32>>,
+    pub illum: Option<u32>
+}
+
+impl MtlMaterial {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            diffuse: None,
+            diffuse_map: None,
+            specular: None,
+            shininess: None,
+            ior: None,
+            dissolve: None,
+            emission: None,
+            illum: None
+        }
+    }
+
+    // Picks the closest of our materials, in order of precedence: anything with
+    // `Ke` is a light, anything transparent (`d` < 1 or a glass illumination
+    // model) is a dielectric, a specular color brighter than the diffuse one
+    // makes a metal, and everything else is lambertian. A diffuse map that
+    // can't be loaded adds a warning and leaves the plain diffuse color.
+    pub fn to_material<'a>(&self, base_dir: &Path, textures: &'a Arena<Texture<'a>>, warnings: &mut Vec<String>) -> Material<'a> {
+        let brightest = |c: Vector3<f32>| c.x.max(c.y).max(c.z);
+
+        if let Some(ke) = self.emission.filter(|&ke| brightest(ke) > 0.0) {
+            return Material::diffuse_light(textures.alloc(Texture::constant(ke.x, ke.y, ke.z)));
+        }
+
+        let transparent = self.dissolve.is_some_and(|d| d < 1.0);
+        let glass_illum = matches!(self.illum, Some(4) | Some(6) | Some(7) | Some(9));
+        if transparent || glass_illum {
+            return Material::dielectric(self.ior.unwrap_or(1.5));
+        }
+
+        let diffuse = self.diffuse.unwrap_or_else(|| Vector3::new(0.8, 0.8, 0.8));
+        if let Some(ks) = self.specular.filter(|&ks| self.diffuse_map.is_none() && brightest(ks) > brightest(diffuse)) {
+            // map the Phong exponent (0..1000) to a roughness, sharper highlights meaning less fuzz
+            let fuzz = (2.0 / (self.shininess.unwrap_or(0.0).max(0.0) + 2.0)).sqrt();
+            return Material::metal(textures.alloc(Texture::constant(ks.x, ks.y, ks.z)), fuzz);
+        }
+
+        let constant = Texture::constant(diffuse.x, diffuse.y, diffuse.z);
+        let albedo = match &self.diffuse_map {
+            Some(map) => {
+                let file = base_dir.join(map);
+                match Texture::load_image(&file.to_string_lossy(), false) {
+                    Ok(texture) => texture,
+                    Err(e) => {
+                        warnings.push(format!("material '{}': could not load map_Kd {}: {}", self.name, file.display(), e));
+                        constant
+                    }
+                }
+            },
+            None => constant
+        };
+        Material::lambertian(textures.alloc(albedo))
+    }
+}
+
+pub struct MaterialLibrary {
+    pub materials: Vec<MtlMaterial>,
+    // statements that were skipped because they aren't supported
+    pub warnings: Vec<String>
+}
+
+pub fn load(path: &Path) -> Result<MaterialLibrary, ObjError> {
+    let file = File::open(path)?;
+    parse(BufReader::new(file))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Result<MaterialLibrary, ObjError> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    let mut warnings = Vec::new();
+    // only warn about each unsupported statement once, libraries tend to repeat them per material
+    let mut warned: HashSet<String> = HashSet::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line?;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => &line[..]
+        };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let args: Vec<&str> = tokens.collect();
+        let error = |message: String| ObjError::Parse { line: line_number, message };
+
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(error(String::from("newmtl needs a material name")));
+            }
+            materials.push(MtlMaterial::new(args.join(" ")));
+            continue;
+        }
+
+        let current = match materials.last_mut() {
+            Some(m) => m,
+            None => return Err(error(format!("'{}' before any newmtl statement", keyword)))
+        };
+
+        match keyword {
+            "Kd" => current.diffuse = Some(parse_color(&args).map_err(error)?),
+            "Ks" => current.specular = Some(parse_color(&args).map_err(error)?),
+            "Ke" => current.emission = Some(parse_color(&args).map_err(error)?),
+            "Ns" => current.shininess = Some(parse_float(&args).map_err(error)?),
+            "Ni" => current.ior = Some(parse_float(&args).map_err(error)?),
+            "d" => current.dissolve = Some(parse_float(&args).map_err(error)?),
+            // Tr is the inverse of d in some exporters
+            "Tr" => current.dissolve = Some(1.0 - parse_float(&args).map_err(error)?),
+            "illum" => {
+                let illum = parse_float(&args).map_err(error)?;
+                current.illum = Some(illum as u32);
+            },
+            "map_Kd" => {
+                // options such as -s or -o come before the file name, which is always last
+                match args.last() {
+                    Some(file) => current.diffuse_map = Some(file.to_string()),
+                    None => return Err(error(String::from("map_Kd needs a file name")))
+                }
+            },
+            _ => {
+                if warned.insert(keyword.to_string()) {
+                    warnings.push(format!("line {}: ignoring unsupported statement '{}'", line_number, keyword));
+                }
+            }
+        }
+    }
+
+    Ok(MaterialLibrary {
+        materials,
+        warnings
+    })
+}
+
+fn parse_float(args: &[&str]) -> Result<f32, String> {
+    match args {
+        [a] => a.parse::<f32>().map_err(|e| format!("invalid number '{}': {}", a, e)),
+        _ => Err(format!("expected 1 number, found {}", args.len()))
+    }
+}
+
+fn parse_color(args: &[&str]) -> Result<Vector3<f32>, String> {
+    let parse = |a: &str| a.parse::<f32>().map_err(|e| format!("invalid number '{}': {}", a, e));
+    match args {
+        // a single value is a grey
+        [a] => {
+            let c = parse(a)?;
+            Ok(Vector3::new(c, c, c))
+        },
+        [r, g, b] => Ok(Vector3::new(parse(r)?, parse(g)?, parse(b)?)),
+        // spectral and CIE XYZ colors are rarely used and we can't represent them
+        ["spectral", ..] | ["xyz", ..] => Err(format!("unsupported color type '{}'", args[0])),
+        _ => Err(format!("expected 1 or 3 numbers, found {}", args.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Textured;
+
+    fn parse_str(source: &str) -> MaterialLibrary {
+        parse(source.as_bytes()).unwrap()
+    }
+
+    fn convert<'a>(source: &str, textures: &'a Arena<Texture<'a>>, warnings: &mut Vec<String>) -> Material<'a> {
+        parse_str(source).materials[0].to_material(Path::new(""), textures, warnings)
+    }
+
+    fn color(texture: &Texture) -> Vector3<f32> {
+        texture.value(0.0, 0.0, &Vector3::new(0.0, 0.0, 0.0))
+    }
+
+    #[test]
+    fn parses_supported_statements() {
+        let library = parse_str("# materials\nnewmtl shiny red\nKd 1 0 0\nKs 0.5\nNs 100\nNi 1.33\nTr 0.25\nillum 2\nmap_Kd -s 2 2 2 red.png\n");
+        let m = &library.materials[0];
+        assert_eq!(m.name, "shiny red");
+        assert_eq!(m.diffuse, Some(Vector3::new(1.0, 0.0, 0.0)));
+        assert_eq!(m.specular, Some(Vector3::new(0.5, 0.5, 0.5)));
+        assert_eq!(m.shininess, Some(100.0));
+        assert_eq!(m.ior, Some(1.33));
+        assert_eq!(m.dissolve, Some(0.75));
+        assert_eq!(m.illum, Some(2));
+        assert_eq!(m.diffuse_map.as_deref(), Some("red.png"));
+        assert!(library.warnings.is_empty());
+    }
+
+    #[test]
+    fn warns_once_per_unsupported_statement() {
+        let library = parse_str("newmtl a\nKa 0 0 0\nnewmtl b\nKa 0 0 0\nmap_Bump b.png\n");
+        assert_eq!(library.materials.len(), 2);
+        assert_eq!(library.warnings, vec![
+            "line 2: ignoring unsupported statement 'Ka'",
+            "line 5: ignoring unsupported statement 'map_Bump'"
+        ]);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let line = |source: &str| match parse(source.as_bytes()) {
+            Err(ObjError::Parse { line, .. }) => line,
+            _ => panic!("expected a parse error for {:?}", source)
+        };
+        assert_eq!(line("Kd 1 1 1\n"), 1);
+        assert_eq!(line("newmtl a\nKd 1 1\n"), 2);
+        assert_eq!(line("newmtl a\n\nNs high\n"), 3);
+        assert_eq!(line("newmtl\n"), 1);
+    }
+
+    #[test]
+    fn maps_to_the_closest_material() {
+        let textures = Arena::new();
+        let mut warnings = Vec::new();
+
+        match convert("newmtl lamp\nKd 1 1 1\nKe 4 4 2\n", &textures, &mut warnings) {
+            Material::DiffuseLight(light) => assert_eq!(color(light.emit), Vector3::new(4.0, 4.0, 2.0)),
+            _ => panic!("Ke should make a light")
+        }
+        match convert("newmtl glass\nd 0.5\nNi 1.7\n", &textures, &mut warnings) {
+            Material::Dielectric(d) => assert_eq!(d.ref_idx, 1.7),
+            _ => panic!("d < 1 should make a dielectric")
+        }
+        match convert("newmtl glass\nillum 7\n", &textures, &mut warnings) {
+            Material::Dielectric(d) => assert_eq!(d.ref_idx, 1.5),
+            _ => panic!("a glass illumination model should make a dielectric")
+        }
+        match convert("newmtl chrome\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 0\n", &textures, &mut warnings) {
+            Material::Metal(m) => {
+                assert_eq!(color(m.albedo), Vector3::new(0.9, 0.9, 0.9));
+                assert_eq!(m.fuzz, 1.0);
+            },
+            _ => panic!("a bright specular color should make a metal")
+        }
+        match convert("newmtl clay\nKd 0.5 0.4 0.3\nKs 0.1 0.1 0.1\n", &textures, &mut warnings) {
+            Material::Lambertian(l) => assert_eq!(color(l.albedo), Vector3::new(0.5, 0.4, 0.3)),
+            _ => panic!("everything else should be lambertian")
+        }
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn warns_about_missing_diffuse_maps() {
+        let textures = Arena::new();
+        let mut warnings = Vec::new();
+        match convert("newmtl wood\nKd 0.6 0.3 0.1\nmap_Kd no-such-file.png\n", &textures, &mut warnings) {
+            Material::Lambertian(l) => assert_eq!(color(l.albedo), Vector3::new(0.6, 0.3, 0.1)),
+            _ => panic!("a diffuse map should make a lambertian")
+        }
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("material 'wood': could not load map_Kd no-such-file.png"));
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader };
+use std::path::Path;
+
+use typed_arena::Arena;
+
+use crate::hitable::Geometry;
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::mtl;
+use crate::texture::Texture;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    // an error inside a material library referenced by the model
+    Library { path: String, error: Box<ObjError> }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(e) => write!(f, "{}", e),
+            ObjError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            ObjError::Library { path, error } => write!(f, "{}: {}", path, error)
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+impl From<io::Error> for ObjError {
+    fn from(e: io::Error) -> Self {
+        ObjError::Io(e)
+    }
+}
+
+// One group of faces from the file, already triangulated and re-indexed so that
+// positions, normals and texture coordinates share a single index buffer.
+pub struct ObjMesh {
+    pub name: String,
+    pub material: Option<String>,
+    pub vertices: Vec<f32>,
+    pub normals: Option<Vec<f32>>,
+    pub uvs: Option<Vec<f32>>,
+    pub indices: Vec<usize>
+}
+
+impl ObjMesh {
+    pub fn into_geometry<'material>(self, material: &'material Material) -> Geometry<'material> {
+        let mut mesh = Mesh::new(self.vertices, self.indices, material);
+        if let Some(normals) = self.normals {
+            mesh = mesh.with_normals(normals);
+        }
+        if let Some(uvs) = self.uvs {
+            mesh = mesh.with_uvs(uvs);
+        }
+        Geometry::Mesh(mesh)
+    }
+}
+
+pub struct ObjModel {
+    pub meshes: Vec<ObjMesh>,
+    // file names from `mtllib` statements, relative to the OBJ file
+    pub material_libraries: Vec<String>,
+    // statements that were skipped because they aren't supported
+    pub warnings: Vec<String>
+}
+
+pub fn load(path: &Path) -> Result<ObjModel, ObjError> {
+    let file = File::open(path)?;
+    parse(BufReader::new(file))
+}
+
+// Loads a model along with the material libraries it references, allocating the
+// converted materials in the given arenas. Groups without a known material use
+// `default_material`. Problems that don't stop the model from rendering, such
+// as a missing library, are printed as warnings.
+pub fn load_geometry<'a>(
+    path: &Path,
+    default_material: &'a Material<'a>,
+    textures: &'a Arena<Texture<'a>>,
+    materials: &'a Arena<Material<'a>>) -> Result<Vec<Geometry<'a>>, ObjError> {
+    let model = load(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut warnings = model.warnings;
+
+    let mut library: HashMap<String, &'a Material<'a>> = HashMap::new();
+    for lib_name in &model.material_libraries {
+        let lib_path = base_dir.join(lib_name);
+        let lib = match mtl::load(&lib_path) {
+            Ok(lib) => lib,
+            Err(ObjError::Io(e)) => {
+                warnings.push(format!("could not open material library {}: {}", lib_path.display(), e));
+                continue;
+            },
+            Err(e) => return Err(ObjError::Library { path: lib_path.display().to_string(), error: Box::new(e) })
+        };
+        warnings.extend(lib.warnings.iter().map(|w| format!("{}: {}", lib_name, w)));
+        for m in &lib.materials {
+            library.insert(m.name.clone(), materials.alloc(m.to_material(base_dir, textures, &mut warnings)));
+        }
+    }
+
+    let geometry = model.meshes.into_iter().map(|mesh| {
+        let material = match &mesh.material {
+            Some(name) => match library.get(name) {
+                Some(m) => *m,
+                None => {
+                    warnings.push(format!("group '{}' uses unknown material '{}'", mesh.name, name));
+                    default_material
+                }
+            },
+            None => default_material
+        };
+        mesh.into_geometry(material)
+    }).collect();
+
+    warnings.iter().for_each(|w| println!("{}: {}", path.display(), w));
+    Ok(geometry)
+}
+
+// A face corner: position, texture coordinate and normal indices, all zero-based.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+    name: String,
+    material: Option<String>,
+    corners: HashMap<Corner, usize>,
+    vertices: Vec<f32>,
+    normals: Vec<f32>,
+    uvs: Vec<f32>,
+    all_normals: bool,
+    all_uvs: bool,
+    indices: Vec<usize>
+}
+
+impl MeshBuilder {
+    fn new(name: String, material: Option<String>) -> Self {
+        Self {
+            name,
+            material,
+            corners: HashMap::new(),
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            all_normals: true,
+            all_uvs: true,
+            indices: Vec::new()
+        }
+    }
+
+    fn corner(&mut self, corner: Corner, positions: &[[f32; 3]], uvs: &[[f32; 2]], normals: &[[f32; 3]]) -> usize {
+        if let Some(&index) = self.corners.get(&corner) {
+            return index;
+        }
+
+        let (v, vt, vn) = corner;
+        let index = self.vertices.len() / 3;
+        self.vertices.extend_from_slice(&positions[v]);
+        match vt {
+            Some(vt) => self.uvs.extend_from_slice(&uvs[vt]),
+            None => {
+                self.all_uvs = false;
+                self.uvs.extend_from_slice(&[0.0, 0.0]);
+            }
+        }
+        match vn {
+            Some(vn) => self.normals.extend_from_slice(&normals[vn]),
+            None => {
+                self.all_normals = false;
+                self.normals.extend_from_slice(&[0.0, 0.0, 0.0]);
+            }
+        }
+        self.corners.insert(corner, index);
+        index
+    }
+
+    fn finish(self) -> Option<ObjMesh> {
+        if self.indices.is_empty() {
+            return None;
+        }
+
+        // attributes only make sense if every corner in the group supplied them
+        Some(ObjMesh {
+            name: self.name,
+            material: self.material,
+            vertices: self.vertices,
+            normals: if self.all_normals { Some(self.normals) } else { None },
+            uvs: if self.all_uvs { Some(self.uvs) } else { None },
+            indices: self.indices
+        })
+    }
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Result<ObjModel, ObjError> {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+
+    let mut meshes = Vec::new();
+    let mut material_libraries = Vec::new();
+    let mut warnings = Vec::new();
+    let mut current = MeshBuilder::new(String::from("default"), None);
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line?;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => &line[..]
+        };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let args: Vec<&str> = tokens.collect();
+        let error = |message: String| ObjError::Parse { line: line_number, message };
+
+        match keyword {
+            "v" => {
+                let v = parse_floats(&args, 3, 4).map_err(error)?;
+                positions.push([v[0], v[1], v[2]]);
+            },
+            "vt" => {
+                let vt = parse_floats(&args, 1, 3).map_err(error)?;
+                uvs.push([vt[0], if vt.len() > 1 { vt[1] } else { 0.0 }]);
+            },
+            "vn" => {
+                let vn = parse_floats(&args, 3, 3).map_err(error)?;
+                normals.push([vn[0], vn[1], vn[2]]);
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(format!("face needs at least 3 vertices, found {}", args.len())));
+                }
+                let mut face = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let corner = parse_corner(arg, positions.len(), uvs.len(), normals.len()).map_err(error)?;
+                    face.push(current.corner(corner, &positions, &uvs, &normals));
+                }
+                // fan triangulation, assumes convex polygons
+                for k in 1..face.len() - 1 {
+                    current.indices.extend_from_slice(&[face[0], face[k], face[k + 1]]);
+                }
+            },
+            "o" | "g" => {
+                let name = if args.is_empty() { String::from("default") } else { args.join(" ") };
+                let material = current.material.clone();
+                meshes.extend(std::mem::replace(&mut current, MeshBuilder::new(name, material)).finish());
+            },
+            "usemtl" => {
+                if args.is_empty() {
+                    return Err(error(String::from("usemtl needs a material name")));
+                }
+                let name = current.name.clone();
+                let material = Some(args.join(" "));
+                meshes.extend(std::mem::replace(&mut current, MeshBuilder::new(name, material)).finish());
+            },
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(error(String::from("mtllib needs a file name")));
+                }
+                material_libraries.extend(args.iter().map(|a| a.to_string()));
+            },
+            // smoothing groups don't matter once per-vertex normals are given
+            "s" => {},
+            _ => warnings.push(format!("line {}: ignoring unsupported statement '{}'", line_number, keyword))
+        }
+    }
+
+    meshes.extend(current.finish());
+
+    Ok(ObjModel {
+        meshes,
+        material_libraries,
+        warnings
+    })
+}
+
+fn parse_floats(args: &[&str], min: usize, max: usize) -> Result<Vec<f32>, String> {
+    if args.len() < min || args.len() > max {
+        return Err(format!("expected {} to {} numbers, found {}", min, max, args.len()));
+    }
+    args.iter()
+        .map(|a| a.parse::<f32>().map_err(|e| format!("invalid number '{}': {}", a, e)))
+        .collect()
+}
+
+// Resolves a one-based (or negative, relative to the end) OBJ index against the
+// number of elements declared so far.
+fn resolve_index(token: &str, count: usize, what: &str) -> Result<usize, String> {
+    let index = token.parse::<isize>().map_err(|e| format!("invalid {} index '{}': {}", what, token, e))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else if index < 0 {
+        count as isize + index
+    } else {
+        return Err(format!("{} index must not be 0", what));
+    };
+
+    if resolved < 0 || resolved as usize >= count {
+        return Err(format!("{} index {} is out of range, {} defined so far", what, index, count));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_corner(token: &str, positions: usize, uvs: usize, normals: usize) -> Result<Corner, String> {
+    let mut parts = token.split('/');
+    let v = resolve_index(parts.next().unwrap_or(""), positions, "vertex")?;
+    let vt = match parts.next() {
+        Some("") | None => None,
+        Some(t) => Some(resolve_index(t, uvs, "texture coordinate")?)
+    };
+    let vn = match parts.next() {
+        Some("") | None => None,
+        Some(n) => Some(resolve_index(n, normals, "normal")?)
+    };
+    if parts.next().is_some() {
+        return Err(format!("face vertex '{}' has too many components", token));
+    }
+    Ok((v, vt, vn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(source: &str) -> Result<ObjModel, ObjError> {
+        parse(source.as_bytes())
+    }
+
+    fn parse_error(source: &str) -> (usize, String) {
+        match parse_str(source) {
+            Err(ObjError::Parse { line, message }) => (line, message),
+            Err(e) => panic!("expected a parse error, got {}", e),
+            Ok(_) => panic!("expected a parse error")
+        }
+    }
+
+    #[test]
+    fn fan_triangulates_polygons() {
+        let model = parse_str("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\nf 1 2 3 4 5\n").unwrap();
+        assert_eq!(model.meshes.len(), 1);
+        let mesh = &model.meshes[0];
+        assert_eq!(mesh.vertices.len(), 15);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4]);
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_end() {
+        let absolute = parse_str("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let relative = parse_str("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n").unwrap();
+        assert_eq!(absolute.meshes[0].vertices, relative.meshes[0].vertices);
+        assert_eq!(absolute.meshes[0].indices, relative.meshes[0].indices);
+    }
+
+    #[test]
+    fn shared_corners_are_reused() {
+        let model = parse_str("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3\nf 1 3 4\n").unwrap();
+        let mesh = &model.meshes[0];
+        assert_eq!(mesh.vertices.len(), 12);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn normals_and_uvs_follow_their_corners() {
+        let model = parse_str("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.5 0.25\nvn 0 0 1\nf 1/1/1 2/1/1 3/1/1\n").unwrap();
+        let mesh = &model.meshes[0];
+        assert_eq!(mesh.normals, Some(vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0]));
+        assert_eq!(mesh.uvs, Some(vec![0.5, 0.25, 0.5, 0.25, 0.5, 0.25]));
+    }
+
+    #[test]
+    fn attributes_are_dropped_unless_every_corner_has_them() {
+        let model = parse_str("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3\n").unwrap();
+        assert_eq!(model.meshes[0].normals, None);
+        assert_eq!(model.meshes[0].uvs, None);
+    }
+
+    #[test]
+    fn groups_and_materials_split_meshes() {
+        let source = "mtllib a.mtl b.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                      g first\nusemtl red\nf 1 2 3\nusemtl blue\nf 1 2 3\ng second\nf 1 2 3\n";
+        let model = parse_str(source).unwrap();
+        assert_eq!(model.material_libraries, vec!["a.mtl", "b.mtl"]);
+        let groups: Vec<(&str, Option<&str>)> = model.meshes.iter().map(|m| (&m.name[..], m.material.as_deref())).collect();
+        assert_eq!(groups, vec![("first", Some("red")), ("first", Some("blue")), ("second", Some("blue"))]);
+    }
+
+    #[test]
+    fn unsupported_statements_become_warnings() {
+        let model = parse_str("v 0 0 0 # a comment\nl 1 2\ns off\n").unwrap();
+        assert_eq!(model.warnings, vec!["line 2: ignoring unsupported statement 'l'"]);
+        assert!(model.meshes.is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_report_their_line() {
+        assert_eq!(parse_error("v 0 0 0\nv 1 0\n"), (2, String::from("expected 3 to 4 numbers, found 2")));
+        assert_eq!(parse_error("v 0 0 x\n").0, 1);
+        assert_eq!(parse_error("v 0 0 0\nv 1 0 0\n\nf 1 2\n"), (4, String::from("face needs at least 3 vertices, found 2")));
+        assert_eq!(parse_error("v 0 0 0\nf 0 1 1\n"), (2, String::from("vertex index must not be 0")));
+        assert_eq!(
+            parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n"),
+            (4, String::from("vertex index 4 is out of range, 3 defined so far"))
+        );
+        assert_eq!(
+            parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2 3\n"),
+            (4, String::from("texture coordinate index 1 is out of range, 0 defined so far"))
+        );
+        assert_eq!(
+            parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvn 0 0 1\nf 1/1/1/1 2 3\n"),
+            (6, String::from("face vertex '1/1/1/1' has too many components"))
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+
+use cgmath::Vector3;
+use image::{ ColorType, ImageFormat, Rgb };
+use image::hdr::HDREncoder;
+
+use crate::aov::{ Aov, AovBuffers };
+use crate::exr::{ self, PixelType };
+use crate::framebuffer::{ self, Framebuffer };
+use crate::tonemap::ToneMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Exr(PixelType),
+    Hdr,
+    Pfm,
+    // plain text P3 when `ascii` is set, binary P6 otherwise
+    Ppm { ascii: bool }
+}
+
+impl OutputFormat {
+    // Picks the format from the file extension, so that an unusable output path
+    // is reported before rendering rather than after.
+    pub fn from_path(path: &Path, exr_type: PixelType, ppm_ascii: bool) -> Result<Self, String> {
+        let ext = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match &ext[..] {
+            "png" => Ok(OutputFormat::Png),
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpeg),
+            "bmp" => Ok(OutputFormat::Bmp),
+            "exr" => Ok(OutputFormat::Exr(exr_type)),
+            "hdr" => Ok(OutputFormat::Hdr),
+            "pfm" => Ok(OutputFormat::Pfm),
+            "ppm" => Ok(OutputFormat::Ppm { ascii: ppm_ascii }),
+            _ => Err(format!("unsupported output format '{}', expected png, jpg, bmp, exr, hdr, pfm or ppm", ext))
+        }
+    }
+}
+
+// Writes the framebuffer, keeping the full linear range for EXR, HDR and PFM
+// and tone mapping to 8 bits like the preview for everything else.
+pub fn save(path: &Path, format: OutputFormat, framebuffer: &Framebuffer, tone_map: &ToneMap) -> io::Result<()> {
+    match format {
+        OutputFormat::Exr(pixel_type) => save_exr(path, pixel_type, framebuffer, &[]),
+        OutputFormat::Hdr => save_hdr(path, framebuffer),
+        OutputFormat::Pfm => save_pfm(path, framebuffer),
+        _ => save_ldr(path, format, framebuffer.width, framebuffer.height, &framebuffer.to_argb(tone_map))
+    }
+}
+
+// Writes the beauty image with the requested auxiliary passes and returns the
+// paths written. EXR keeps everything in one file with a layer per pass, other
+// formats get a file per pass named like `render.normal.png`. Float formats
+// store the raw pass values, 8-bit ones a viewable version of them.
+pub fn save_with_aovs(path: &Path, format: OutputFormat, framebuffer: &Framebuffer, tone_map: &ToneMap, aovs: &AovBuffers, passes: &[Aov]) -> io::Result<Vec<PathBuf>> {
+    if let OutputFormat::Exr(pixel_type) = format {
+        let layers: Vec<(String, Vec<f32>, PixelType)> = passes.iter().flat_map(|&aov| {
+            let layer_type = if aov.needs_full_precision() { PixelType::Float } else { pixel_type };
+            aovs.channels(aov).into_iter().map(move |(name, data)| (name, data, layer_type))
+        }).collect();
+        save_exr(path, pixel_type, framebuffer, &layers)?;
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    save(path, format, framebuffer, tone_map)?;
+    let mut written = vec![path.to_path_buf()];
+    for &aov in passes {
+        let aov_path = pass_path(path, aov.name());
+        match format {
+            OutputFormat::Hdr | OutputFormat::Pfm => save(&aov_path, format, &aovs.to_framebuffer(aov), &ToneMap::default())?,
+            _ => save_ldr(&aov_path, format, aovs.width, aovs.height, &aovs.to_argb(aov))?
+        }
+        written.push(aov_path);
+    }
+    Ok(written)
+}
+
+// Writes how many samples each pixel took. Float formats get the raw counts,
+// 8-bit ones a color scale from blue for the fewest to red for the most.
+pub fn save_heatmap(path: &Path, format: OutputFormat, width: usize, height: usize, counts: &[u32]) -> io::Result<()> {
+    match format {
+        OutputFormat::Exr(_) | OutputFormat::Hdr | OutputFormat::Pfm => {
+            let framebuffer = Framebuffer {
+                width,
+                height,
+                pixels: counts.iter().map(|&c| Vector3::new(c as f32, c as f32, c as f32)).collect()
+            };
+            save(path, format, &framebuffer, &ToneMap::default())
+        },
+        _ => {
+            let min = counts.iter().copied().min().unwrap_or(0);
+            let max = counts.iter().copied().max().unwrap_or(0);
+            let argb: Vec<u32> = counts.iter().map(|&c| {
+                let t = if max > min { (c - min) as f32 / (max - min) as f32 } else { 0.0 };
+                heat_color(t)
+            }).collect();
+            save_ldr(path, format, width, height, &argb)
+        }
+    }
+}
+
+// Blue, cyan, green, yellow, red, interpolated over `t` in [0, 1].
+fn heat_color(t: f32) -> u32 {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0]
+    ];
+    let x = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (x as usize).min(STOPS.len() - 2);
+    let f = x - i as f32;
+    let channel = |c: usize| (255.0 * (STOPS[i][c] + f * (STOPS[i + 1][c] - STOPS[i][c]))) as u32;
+    framebuffer::argb(channel(0), channel(1), channel(2))
+}
+
+// `dir/render.png` becomes `dir/render.<pass>.png`.
+pub fn pass_path(path: &Path, pass: &str) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!("{}.{}.{}", stem, pass, ext))
+}
+
+fn save_ldr(path: &Path, format: OutputFormat, width: usize, height: usize, argb: &[u32]) -> io::Result<()> {
+    let image_format = match format {
+        OutputFormat::Png => ImageFormat::PNG,
+        OutputFormat::Jpeg => ImageFormat::JPEG,
+        OutputFormat::Bmp => ImageFormat::BMP,
+        OutputFormat::Ppm { ascii } => return save_ppm(path, ascii, width, height, argb),
+        _ => unreachable!("{:?} is not an 8-bit format", format)
+    };
+
+    let rgb: Vec<u8> = argb.iter()
+        .flat_map(|&pixel| vec![(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        .collect();
+
+    image::save_buffer_with_format(path, &rgb, width as u32, height as u32, ColorType::RGB(8), image_format)
+}
+
+// `layers` are extra named channels written after the RGB of the beauty pass,
+// each with its own pixel type.
+fn save_exr(path: &Path, pixel_type: PixelType, framebuffer: &Framebuffer, layers: &[(String, Vec<f32>, PixelType)]) -> io::Result<()> {
+    let r: Vec<f32> = framebuffer.pixels.iter().map(|p| p.x).collect();
+    let g: Vec<f32> = framebuffer.pixels.iter().map(|p| p.y).collect();
+    let b: Vec<f32> = framebuffer.pixels.iter().map(|p| p.z).collect();
+    let mut channels = vec![
+        exr::Channel { name: String::from("R"), data: &r, pixel_type },
+        exr::Channel { name: String::from("G"), data: &g, pixel_type },
+        exr::Channel { name: String::from("B"), data: &b, pixel_type }
+    ];
+    channels.extend(layers.iter().map(|(name, data, pixel_type)| exr::Channel { name: name.clone(), data, pixel_type: *pixel_type }));
+
+    let mut file = BufWriter::new(File::create(path)?);
+    exr::write(&mut file, framebuffer.width, framebuffer.height, &mut channels)
+}
+
+fn save_hdr(path: &Path, framebuffer: &Framebuffer) -> io::Result<()> {
+    // RGBE can't store negative values or NaN
+    let rgb: Vec<Rgb<f32>> = framebuffer.pixels.iter()
+        .map(|p| Rgb([p.x.max(0.0), p.y.max(0.0), p.z.max(0.0)]))
+        .collect();
+
+    let file = BufWriter::new(File::create(path)?);
+    HDREncoder::new(file).encode(&rgb, framebuffer.width, framebuffer.height)
+}
+
+// Portable float map: a text header, then little endian RGB floats with the
+// bottom row first. The negative scale in the header marks the byte order.
+fn save_pfm(path: &Path, framebuffer: &Framebuffer) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_pfm(&mut file, framebuffer)?;
+    file.flush()
+}
+
+fn write_pfm<W: Write>(w: &mut W, framebuffer: &Framebuffer) -> io::Result<()> {
+    write!(w, "PF\n{} {}\n-1.0\n", framebuffer.width, framebuffer.height)?;
+    for row in framebuffer.pixels.chunks(framebuffer.width).rev() {
+        for p in row {
+            w.write_all(&p.x.to_le_bytes())?;
+            w.write_all(&p.y.to_le_bytes())?;
+            w.write_all(&p.z.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn save_ppm(path: &Path, ascii: bool, width: usize, height: usize, argb: &[u32]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_ppm(&mut file, ascii, width, height, argb)?;
+    file.flush()
+}
+
+fn write_ppm<W: Write>(w: &mut W, ascii: bool, width: usize, height: usize, argb: &[u32]) -> io::Result<()> {
+    write!(w, "{}\n{} {}\n255\n", if ascii { "P3" } else { "P6" }, width, height)?;
+    for row in argb.chunks(width) {
+        for &pixel in row {
+            let rgb = [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8];
+            if ascii {
+                write!(w, "{} {} {} ", rgb[0], rgb[1], rgb[2])?;
+            } else {
+                w.write_all(&rgb)?;
+            }
+        }
+        if ascii {
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f32_at(bytes: &[u8], at: usize) -> f32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        f32::from_le_bytes(word)
+    }
+
+    #[test]
+    fn writes_pfm_bottom_row_first() {
+        let framebuffer = Framebuffer {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                Vector3::new(1.0, 2.0, 3.0), Vector3::new(4.0, 5.0, 6.0),
+                Vector3::new(-1.5, 0.25, 1e6), Vector3::new(7.0, 8.0, 9.0)
+            ]
+        };
+        let mut bytes = Vec::new();
+        write_pfm(&mut bytes, &framebuffer).unwrap();
+
+        // a negative scale means little endian
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let data = &bytes[header.len()..];
+        assert_eq!(data.len(), 4 * 3 * 4);
+
+        assert_eq!(&data[..4], &[0x00, 0x00, 0xc0, 0xbf]);
+        let floats: Vec<f32> = (0..12).map(|i| f32_at(data, 4 * i)).collect();
+        assert_eq!(floats, vec![-1.5, 0.25, 1e6, 7.0, 8.0, 9.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn writes_ppm_top_row_first() {
+        let argb = [
+            framebuffer::argb(255, 0, 0), framebuffer::argb(0, 255, 0),
+            framebuffer::argb(0, 0, 255), framebuffer::argb(1, 2, 3)
+        ];
+
+        let mut binary = Vec::new();
+        write_ppm(&mut binary, false, 2, 2, &argb).unwrap();
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 1, 2, 3]);
+        assert_eq!(binary, expected);
+
+        let mut ascii = Vec::new();
+        write_ppm(&mut ascii, true, 2, 2, &argb).unwrap();
+        assert_eq!(String::from_utf8(ascii).unwrap(), "P3\n2 2\n255\n255 0 0 0 255 0 \n0 0 255 1 2 3 \n");
+    }
+}
@@ -1,29 +1,28 @@
 use cgmath::{ dot, Vector3, InnerSpace };
 use rand::prelude::*;
 
-fn permute(p: &mut[u8], n: usize) {
-    for i in (0..n as usize).rev() {
-        let target = (random::<f32>() * (i + 1) as f32) as usize;
+use crate::rng::Pcg32;
+
+fn permute(p: &mut[u8], n: usize, rng: &mut Pcg32) {
+    for i in (0..n).rev() {
+        let target = (rng.gen::<f32>() * (i + 1) as f32) as usize;
         p.swap(i, target);
     }
 }
 
-fn perlin_generate_perm() -> Vec<u8> {
-    let mut p = vec![0; 256];
-    for i in 0..256 {
-        p[i] = i as u8;
-    }
-    permute(&mut p, 256);
+fn perlin_generate_perm(rng: &mut Pcg32) -> Vec<u8> {
+    let mut p: Vec<u8> = (0..=255).collect();
+    permute(&mut p, 256, rng);
     p
 }
 
-fn perlin_generate() -> Vec<Vector3<f32>> {
+fn perlin_generate(rng: &mut Pcg32) -> Vec<Vector3<f32>> {
     let mut p = Vec::with_capacity(256);
-    for i in 0..256 {
+    for _i in 0..256 {
         p.push(Vector3::new(
-            -1.0 + 2.0 * random::<f32>(),
-            -1.0 + 2.0 * random::<f32>(),
-            -1.0 + 2.0 * random::<f32>()
+            -1.0 + 2.0 * rng.gen::<f32>(),
+            -1.0 + 2.0 * rng.gen::<f32>(),
+            -1.0 + 2.0 * rng.gen::<f32>()
         ).normalize());
     }
     p
@@ -37,6 +36,7 @@ fn perlin_generate() -> Vec<Vector3<f32>> {
 //     p
 // }
 
+#[allow(clippy::needless_range_loop)]
 fn perlin_interpolate(c: &[[[Vector3<f32>; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
     let uu = u * u * (3.0 - 2.0 * u);
     let vv = v * v * (3.0 - 2.0 * v);
@@ -84,13 +84,13 @@ pub struct Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Perlin {
+    pub fn new(rng: &mut Pcg32) -> Perlin {
         Perlin {
-            ranvec: perlin_generate(),
+            ranvec: perlin_generate(rng),
             // ranfloat: perlin_generate(),
-            perm_x: perlin_generate_perm(),
-            perm_y: perlin_generate_perm(),
-            perm_z: perlin_generate_perm()
+            perm_x: perlin_generate_perm(rng),
+            perm_y: perlin_generate_perm(rng),
+            perm_z: perlin_generate_perm(rng)
         }
     }
 
@@ -121,9 +121,9 @@ impl Perlin {
 
     pub fn turb(&self, p: Vector3<f32>, depth: u8) -> f32 {
         let mut accum: f32 = 0.0;
-        let mut temp_p = p.clone();
+        let mut temp_p = p;
         let mut weight = 1.0;
-        for i in 0..depth {
+        for _i in 0..depth {
             accum += weight * self.noise(temp_p);
             weight *= 0.5;
             temp_p *= 2.0;
@@ -2,6 +2,7 @@ use cgmath::{
     Vector3
 };
 
+#[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vector3<f32>,
     pub direction: Vector3<f32>,
@@ -1,63 +1,685 @@
-use cgmath::{ Vector3, ElementWise };
-use rand::prelude::*;
+use cgmath::{ Vector3, Vector4, ElementWise, InnerSpace, dot };
 use rayon::prelude::*;
 
-use std::time::Instant;
+use std::fmt;
+use std::sync::{ Mutex, mpsc };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{ Duration, Instant };
 
+use crate::aov::{ self, AovBuffers, AovPixel };
 use crate::camera::Camera;
-use crate::hitable::{ Geometry, hit_list };
+use crate::filter::{ Filter, SplatBuffer, Splats };
+use crate::framebuffer::Framebuffer;
+use crate::hitable::{ Geometry, Hitable, HitRecord };
 use crate::ray::Ray;
-use crate::material::{ Scattered, Emitter };
+use crate::tile::{ self, Tile, TileOrder, TileStats };
+use crate::light::{ Lights, LightStats };
+use crate::material::{ self, Scattered, Emitter };
+use crate::sampler::{ Sampler, SamplerKind };
+use crate::spectrum::{ Channels, Wavelengths };
+use crate::util;
 
 
-fn color(r: Ray, world: &[Geometry], depth: i32, max_depth: i32) -> Vector3<f32> {
-    if let Some(hit) = hit_list(world, &r, 0.001, std::f32::MAX) {
-        if depth < max_depth {
-            let emitted = hit.material.emitted(hit.u, hit.v, &hit.p);
-            if let Some(scatter) = hit.material.scatter(r, &hit) {
-                return emitted + scatter.attenuation.mul_element_wise(color(scatter.ray, world, depth + 1, max_depth));
-            } else {
-                return emitted;
+// How light and BSDF samples that could have found the same light are weighed
+// against each other.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    Power
+}
+
+impl std::str::FromStr for MisHeuristic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "balance" => Ok(MisHeuristic::Balance),
+            "power" => Ok(MisHeuristic::Power),
+            _ => Err(format!("unknown MIS heuristic '{}', expected 'balance' or 'power'", s))
+        }
+    }
+}
+
+impl MisHeuristic {
+    // The weight of a sample taken with density `pdf` when the other strategy
+    // would have produced it with density `other_pdf`.
+    fn weight(self, pdf: f32, other_pdf: f32) -> f32 {
+        if pdf <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            MisHeuristic::Balance => pdf / (pdf + other_pdf),
+            MisHeuristic::Power => pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+        }
+    }
+
+    // The weight of light found by a BSDF sample taken with density `bsdf_pdf`,
+    // which is None after a specular bounce. Light sampling can't find those
+    // paths, so they keep their full weight and `light_pdf` isn't needed.
+    fn bsdf_weight(self, bsdf_pdf: Option<f32>, light_pdf: impl FnOnce() -> f32) -> f32 {
+        match bsdf_pdf {
+            Some(pdf) => self.weight(pdf, light_pdf()),
+            None => 1.0
+        }
+    }
+}
+
+pub struct Settings {
+    pub width: usize,
+    pub height: usize,
+    pub num_samples: i32,
+    pub max_depth: i32,
+    // bounces before Russian roulette may end a path
+    pub rr_depth: i32,
+    pub heuristic: MisHeuristic,
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    // stop sampling a pixel once the standard error of its brightness falls
+    // below this fraction of the brightness, None to always take num_samples
+    pub noise_threshold: Option<f32>,
+    // samples every pixel gets before its noise is trusted
+    pub min_samples: i32,
+    // every sample of every pixel draws its random numbers from its own stream
+    // derived from this, so a seed always gives the same image
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    // how samples are spread over the pixels around them
+    pub filter: Filter,
+    // trace a few wavelengths per path instead of red, green and blue
+    pub spectral: bool
+}
+
+// brightness below which noise is measured against this floor instead, so that
+// black pixels can converge too
+const MIN_NOISE_REFERENCE: f32 = 0.01;
+
+// how often the preview is refreshed while a pass is running
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long paths got and what ended them.
+#[derive(Clone, Copy, Default)]
+pub struct BounceStats {
+    pub paths: u64,
+    pub bounces: u64,
+    pub escaped: u64,
+    pub absorbed: u64,
+    pub roulette: u64,
+    pub depth_limit: u64
+}
+
+impl BounceStats {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            paths: self.paths + other.paths,
+            bounces: self.bounces + other.bounces,
+            escaped: self.escaped + other.escaped,
+            absorbed: self.absorbed + other.absorbed,
+            roulette: self.roulette + other.roulette,
+            depth_limit: self.depth_limit + other.depth_limit
+        }
+    }
+}
+
+impl fmt::Display for BounceStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |n: u64| 100.0 * n as f64 / self.paths.max(1) as f64;
+        write!(
+            f,
+            "{} paths, {:.2} bounces on average, ended by escaping {:.1}%, absorption {:.1}%, Russian roulette {:.1}%, depth limit {:.1}%",
+            self.paths,
+            self.bounces as f64 / self.paths.max(1) as f64,
+            percent(self.escaped),
+            percent(self.absorbed),
+            percent(self.roulette),
+            percent(self.depth_limit)
+        )
+    }
+}
+
+// Follows a path from the camera, starting at the first hit of the camera ray,
+// and returns the light it carries back. Every bounce adds the light emitted at
+// the hit and the light sampled directly from the lights, scaled by the
+// throughput of the path so far. Colors are carried in `channels` and
+// converted back to RGB at the end.
+#[allow(clippy::too_many_arguments)]
+fn trace(r: Ray, first_hit: Option<HitRecord>, world: &dyn Hitable, lights: &Lights, settings: &Settings, stats: &mut BounceStats, sampler: &mut Sampler, mut channels: Channels) -> Vector3<f32> {
+    let mut radiance = Vector4::new(0.0, 0.0, 0.0, 0.0);
+    let mut throughput = Vector4::new(1.0, 1.0, 1.0, 1.0);
+    let mut r = r;
+    let mut hit = first_hit;
+    // the density the last bounce picked `r` with, or None after a specular
+    // bounce or for the camera ray, which light sampling can't reproduce
+    let mut bsdf_pdf: Option<f32> = None;
+    let mut depth = 0;
+
+    stats.paths += 1;
+    loop {
+        let h = match hit {
+            Some(h) => h,
+            None => {
+                stats.escaped += 1;
+                break;
+            }
+        };
+        if depth >= settings.max_depth {
+            stats.depth_limit += 1;
+            break;
+        }
+
+        // every bounce takes the same dimensions from the sampler, whether it
+        // ends up using them or not
+        let light_choice = sampler.get_1d();
+        let light_u = sampler.get_2d();
+        let lobe_choice = sampler.get_1d();
+        let direction_u = sampler.get_2d();
+        let roulette_u = sampler.get_1d();
+
+        let emitted = channels.emission(h.material.emitted(h.u, h.v, &h.p));
+        let weight = settings.heuristic.bsdf_weight(bsdf_pdf, || lights.pdf(&r, &h));
+        radiance += throughput.mul_element_wise(emitted * weight);
+
+        if !h.material.is_specular() {
+            radiance += throughput.mul_element_wise(direct_light(&r, &h, world, lights, settings.heuristic, &channels, light_choice, light_u));
+        }
+
+        let scatter = match h.material.scatter(r, &h, channels.wavelength(), lobe_choice, direction_u) {
+            Some(scatter) => scatter,
+            None => {
+                stats.absorbed += 1;
+                break;
+            }
+        };
+        // only the wavelength the direction was picked for can follow it
+        if let Channels::Spectral(wavelengths) = &mut channels {
+            if h.material.is_dispersive() {
+                wavelengths.terminate_secondary();
+            }
+        }
+        throughput = throughput.mul_element_wise(channels.reflectance(scatter.attenuation));
+        bsdf_pdf = if scatter.specular { None } else { Some(scatter.pdf) };
+        depth += 1;
+        stats.bounces += 1;
+
+        // end dim paths at random, boosting the survivors to stay unbiased
+        if depth >= settings.rr_depth {
+            let survival = channels.max_live(throughput).min(1.0);
+            if roulette_u >= survival {
+                stats.roulette += 1;
+                break;
+            }
+            throughput /= survival;
+        }
+
+        r = scatter.ray;
+        hit = world.hit(&r, 0.001, f32::MAX);
+    }
+
+    channels.to_rgb(radiance)
+}
+
+// Next event estimation: the light reaching a hit straight from a point sampled
+// on the lights, if nothing is in the way, weighed against the chance of the
+// BSDF finding the same point.
+#[allow(clippy::too_many_arguments)]
+fn direct_light(r: &Ray, hit: &HitRecord, world: &dyn Hitable, lights: &Lights, heuristic: MisHeuristic, channels: &Channels, choice: f32, u: (f32, f32)) -> Vector4<f32> {
+    let black = Vector4::new(0.0, 0.0, 0.0, 0.0);
+    let sample = match lights.sample(r.time, choice, u) {
+        Some(sample) => sample,
+        None => return black
+    };
+
+    let to_light = sample.point - hit.p;
+    let distance2 = to_light.magnitude2();
+    let distance = distance2.sqrt();
+    let direction = to_light / distance;
+    // volumes scatter the same amount whichever way the light comes from
+    let cos_surface = if hit.material.is_volume() { 1.0 } else { dot(material::facing_normal(r, hit), direction) };
+    // lights emit from both sides
+    let cos_light = dot(sample.normal, direction).abs();
+    if cos_surface <= 0.0 || cos_light <= 0.0 {
+        return black;
+    }
+
+    // the sampled point must be the first thing the shadow ray reaches, which
+    // also rules out points on the far side of a spherical light
+    let shadow_ray = Ray::new(hit.p, direction, r.time);
+    match world.hit(&shadow_ray, 0.001, distance * 1.001) {
+        Some(light_hit) if light_hit.object == sample.object && light_hit.t > distance * 0.999 => {
+            let emitted = channels.emission(light_hit.material.emitted(light_hit.u, light_hit.v, &light_hit.p));
+            // convert the area density to one over solid angle at the hit
+            let pdf = sample.pdf_area * distance2 / cos_light;
+            let weight = heuristic.weight(pdf, hit.material.pdf(r, hit, direction));
+            channels.reflectance(hit.material.bsdf(r, hit, direction)).mul_element_wise(emitted) * cos_surface * weight / pdf
+        },
+        _ => black
+    }
+}
+
+pub struct Render {
+    pub framebuffer: Framebuffer,
+    pub aovs: AovBuffers,
+    pub stats: BounceStats,
+    pub tiles: TileStats,
+    // the emitters that were sampled directly
+    pub lights: LightStats,
+    // passes over the image, fewer than asked for if the render was cancelled
+    // or every pixel converged early
+    pub samples: i32,
+    // samples taken in each pixel
+    pub sample_counts: Vec<u32>
+}
+
+// The image so far, handed out after every pass over the image and every
+// PREVIEW_INTERVAL while a pass is running.
+pub struct Progress<'a> {
+    pub framebuffer: &'a Framebuffer,
+    pub samples: i32,
+    pub elapsed: Duration,
+    // tiles being rendered right now
+    pub active: &'a [Tile]
+}
+
+// The samples taken in one pixel so far, with a running mean and variance of
+// their brightness (Welford's algorithm) for adaptive sampling. What they add
+// to the image goes through the tile's splat buffer instead.
+#[derive(Clone, Copy)]
+struct PixelState {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    converged: bool
+}
+
+impl PixelState {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            converged: false
+        }
+    }
+
+    fn add(&mut self, sample: Vector3<f32>, settings: &Settings) {
+        self.count += 1;
+        let brightness = util::luminance(sample);
+        let delta = brightness - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (brightness - self.mean);
+
+        if let Some(threshold) = settings.noise_threshold {
+            if self.count >= settings.min_samples.max(2) as u32 {
+                let n = self.count as f32;
+                let standard_error = (self.m2 / ((n - 1.0) * n)).sqrt();
+                self.converged = standard_error <= threshold * self.mean.max(MIN_NOISE_REFERENCE);
+            }
+        }
+    }
+}
+
+// What one tile has accumulated so far.
+struct TileState {
+    tile: Tile,
+    pixels: Vec<PixelState>,
+    splats: SplatBuffer,
+    aov_pixels: Vec<AovPixel>,
+    time: Duration,
+    stats: BounceStats,
+    // every pixel in the tile has converged
+    converged: bool
+}
+
+// Everything a tile needs from the scene, shared by all of them.
+struct Scene<'a> {
+    camera: &'a Camera,
+    world: &'a (dyn Hitable + Sync),
+    lights: &'a Lights<'a>,
+    material_ids: &'a [u32],
+    settings: &'a Settings
+}
+
+// Adds one sample to every pixel of a tile that hasn't converged yet.
+fn render_tile(state: &mut TileState, scene: &Scene) {
+    let start = Instant::now();
+    let tile = state.tile;
+    let f_width = scene.settings.width as f32;
+    let f_height = scene.settings.height as f32;
+
+    for y in 0..tile.height {
+        for x in 0..tile.width {
+            let index = y * tile.width + x;
+            if state.pixels[index].converged {
+                continue;
             }
+            let pixel = (tile.y + y) * scene.settings.width + tile.x + x;
+            let mut sampler = Sampler::new(
+                scene.settings.sampler,
+                scene.settings.seed,
+                pixel,
+                state.pixels[index].count,
+                scene.settings.num_samples as u32
+            );
+            let (jitter_x, jitter_y) = sampler.get_2d();
+            let film_x = (tile.x + x) as f32 + jitter_x;
+            let film_y = (tile.y + y) as f32 + jitter_y;
+            let u = film_x / f_width;
+            let v = 1.0 - film_y / f_height;
+
+            let lens = sampler.get_2d();
+            let time = sampler.get_1d();
+            let channels = if scene.settings.spectral {
+                Channels::Spectral(Wavelengths::sample(sampler.get_1d()))
+            } else {
+                Channels::Rgb
+            };
+            let r = scene.camera.get_ray(u, v, lens, time);
+            let hit = scene.world.hit(&r, 0.001, f32::MAX);
+            state.aov_pixels[index].add(hit.as_ref(), scene.material_ids);
+            let sample = trace(r, hit, scene.world, scene.lights, scene.settings, &mut state.stats, &mut sampler, channels);
+            state.pixels[index].add(sample, scene.settings);
+            state.splats.add(&scene.settings.filter, film_x, film_y, sample);
         }
-        return Vector3::new(0.0, 0.0, 0.0);
-    } else {
-        Vector3::new(0.0, 0.0, 0.0)
     }
+
+    state.converged = state.pixels.iter().all(|pixel| pixel.converged);
+    state.time += start.elapsed();
 }
 
-pub fn draw(camera: Camera, world: Vec<Geometry>, width: usize, height: usize, num_samples: i32, max_depth: i32) -> Vec<u32> {
+// Renders the beauty image along with the auxiliary passes, which come from the
+// first hit of each camera ray. `geometry` is the item list behind `world`.
+// The image is refined one sample per pixel at a time, and in every pass the
+// tiles are handed to the worker threads in the configured order. With a noise
+// threshold, pixels drop out of later passes once they have converged.
+// `on_progress` is called with the image so far regularly, returning false from
+// it stops the render.
+pub fn draw<F>(camera: Camera, world: &(dyn Hitable + Sync), geometry: &[Geometry], settings: &Settings, mut on_progress: F) -> Render
+    where F: FnMut(&Progress) -> bool {
     let now = Instant::now();
-    let mut buffer: Vec<u32> = vec![0; width * height];
+    let (width, height) = (settings.width, settings.height);
+    let mut framebuffer = Framebuffer::new(width, height);
+    let material_ids = aov::material_ids(geometry);
+    let lights = Lights::new(geometry);
 
-    let f_width = width as f32;
-    let f_height = height as f32;
-    let f_samples = num_samples as f32;
-    
-    buffer.par_chunks_mut(width).enumerate().for_each(|(j, row)| {
-        for i in 0..width {
-            let mut col = Vector3::new(0.0, 0.0, 0.0);
-            for _s in 0..num_samples {
-                let u = (i as f32 + random::<f32>()) / f_width;
-                let v = 1.0 - ((j as f32 + random::<f32>()) / f_height);
+    let tiles = tile::tiles(width, height, settings.tile_size, settings.tile_order);
+    let states: Vec<Mutex<TileState>> = tiles.iter().map(|&tile| Mutex::new(TileState {
+        tile,
+        pixels: vec![PixelState::new(); tile.width * tile.height],
+        splats: SplatBuffer::new(&tile, &settings.filter, width, height),
+        aov_pixels: vec![AovPixel::new(); tile.width * tile.height],
+        time: Duration::default(),
+        stats: BounceStats::default(),
+        converged: false
+    })).collect();
+    let active: Vec<AtomicBool> = tiles.iter().map(|_| AtomicBool::new(false)).collect();
+    let cancelled = AtomicBool::new(false);
+    let scene = Scene {
+        camera: &camera,
+        world,
+        lights: &lights,
+        material_ids: &material_ids,
+        settings
+    };
+
+    let mut pass = 0;
+    while pass < settings.num_samples && !cancelled.load(Ordering::Relaxed) {
+        let next = AtomicUsize::new(0);
+        // set when cancelling left some of this pass's tiles unrendered
+        let skipped = AtomicBool::new(false);
+        // every worker keeps taking the next tile in line, so they are started in order
+        let work = || (0..rayon::current_num_threads()).into_par_iter().for_each(|_| loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= states.len() {
+                break;
+            }
+            if cancelled.load(Ordering::Relaxed) {
+                skipped.store(true, Ordering::Relaxed);
+                break;
+            }
+            let mut state = states[index].lock().unwrap();
+            if state.converged {
+                continue;
+            }
+            active[index].store(true, Ordering::Relaxed);
+            render_tile(&mut state, &scene);
+            active[index].store(false, Ordering::Relaxed);
+        });
 
-                let r = camera.get_ray(u, v);
-                col += color(r, &world[..], 0, max_depth);
+        // the pass runs on another thread so this one can keep the preview
+        // responsive, it hangs up the channel when it's done
+        let (done, finished) = mpsc::channel::<()>();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                work();
+                drop(done);
+            });
+            while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(PREVIEW_INTERVAL) {
+                let active_tiles: Vec<Tile> = tiles.iter().zip(&active)
+                    .filter(|(_, a)| a.load(Ordering::Relaxed))
+                    .map(|(&tile, _)| tile)
+                    .collect();
+                let progress = Progress { framebuffer: &framebuffer, samples: pass, elapsed: now.elapsed(), active: &active_tiles };
+                if !cancelled.load(Ordering::Relaxed) && !on_progress(&progress) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
             }
-            col /= f_samples;
+        });
+        // a pass cut short doesn't count, its tiles keep their extra sample
+        if !skipped.load(Ordering::Relaxed) {
+            pass += 1;
+        }
+
+        // samples spill over into neighbouring tiles, so every pixel is the
+        // weighted sum of what all the tiles splatted into it over the sum of
+        // the weights
+        let mut converged = true;
+        let mut splats = Splats::new(width, height);
+        for state in &states {
+            let state = state.lock().unwrap();
+            converged &= state.converged;
+            state.splats.accumulate(&mut splats);
+        }
+        for (index, pixel) in framebuffer.pixels.iter_mut().enumerate() {
+            *pixel = splats.pixel(index);
+        }
+        if converged {
+            println!("Every pixel converged after {} samples", pass);
+            break;
+        }
+
+        if !cancelled.load(Ordering::Relaxed) && !on_progress(&Progress { framebuffer: &framebuffer, samples: pass, elapsed: now.elapsed(), active: &[] }) {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
 
-            let ir = (255.0 * col[0].sqrt()).max(0.0).min(255.0) as u32;
-            let ig = (255.0 * col[1].sqrt()).max(0.0).min(255.0) as u32;
-            let ib = (255.0 * col[2].sqrt()).max(0.0).min(255.0) as u32;
+    let states: Vec<TileState> = states.into_iter().map(|state| state.into_inner().unwrap()).collect();
+    if cancelled.load(Ordering::Relaxed) {
+        println!("Cancelled after {} of {} samples per pixel", pass, settings.num_samples);
+    }
 
-            row[i] = argb(ir, ig, ib);
+    let mut aov_pixels = vec![AovPixel::new(); width * height];
+    let mut sample_counts = vec![0; width * height];
+    for state in &states {
+        let tile = state.tile;
+        for y in 0..tile.height {
+            let start = (tile.y + y) * width + tile.x;
+            let range = y * tile.width..(y + 1) * tile.width;
+            aov_pixels[start..start + tile.width].copy_from_slice(&state.aov_pixels[range.clone()]);
+            for (count, pixel) in sample_counts[start..start + tile.width].iter_mut().zip(&state.pixels[range]) {
+                *count = pixel.count;
+            }
         }
-    });
+    }
+    if settings.noise_threshold.is_some() {
+        let total: u64 = sample_counts.iter().map(|&c| c as u64).sum();
+        println!("Adaptive sampling took {:.1} samples per pixel on average", total as f64 / sample_counts.len().max(1) as f64);
+    }
 
     println!("{} seconds to draw scene", now.elapsed().as_secs());
-    buffer
+    Render {
+        framebuffer,
+        aovs: AovBuffers::from_pixels(width, height, &aov_pixels),
+        stats: states.iter().fold(BounceStats::default(), |acc, state| acc.merge(state.stats)),
+        tiles: TileStats {
+            tiles,
+            times: states.iter().map(|state| state.time).collect()
+        },
+        lights: lights.stats(),
+        samples: pass,
+        sample_counts
+    }
 }
 
-fn argb(r: u32, g: u32, b: u32) -> u32 {
-    255 << 24 | r << 16 | g << 8 | b
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterKind;
+    use crate::material::Material;
+    use crate::rng::Pcg32;
+    use crate::texture::Texture;
+    use rand::Rng;
+
+    const HEURISTICS: [MisHeuristic; 2] = [MisHeuristic::Balance, MisHeuristic::Power];
+
+    fn settings() -> Settings {
+        Settings {
+            width: 8,
+            height: 8,
+            num_samples: 16,
+            max_depth: 8,
+            rr_depth: 3,
+            heuristic: MisHeuristic::Power,
+            tile_size: 4,
+            tile_order: TileOrder::Spiral,
+            noise_threshold: None,
+            min_samples: 8,
+            seed: 7,
+            sampler: SamplerKind::Random,
+            filter: Filter::new(FilterKind::Box, 0.5),
+            spectral: false
+        }
+    }
+
+    fn grey(x: f32) -> Vector3<f32> {
+        Vector3::new(x, x, x)
+    }
+
+    #[test]
+    fn weights_for_the_same_pdfs_sum_to_one() {
+        for &heuristic in &HEURISTICS {
+            for &(a, b) in &[(1.0, 1.0), (0.3, 7.0), (100.0, 0.001), (2.5, 0.0)] {
+                let sum = heuristic.weight(a, b) + heuristic.weight(b, a);
+                assert!((sum - 1.0).abs() < 1e-6, "{:?} weights for {} and {} sum to {}", heuristic, a, b, sum);
+            }
+            // a strategy that can't produce the sample gets no say
+            assert_eq!(heuristic.weight(0.0, 1.0), 0.0);
+        }
+        assert_eq!(MisHeuristic::Balance.weight(1.0, 3.0), 0.25);
+        assert_eq!(MisHeuristic::Power.weight(1.0, 3.0), 0.1);
+    }
+
+    #[test]
+    fn specular_bounces_keep_full_weight() {
+        for &heuristic in &HEURISTICS {
+            assert_eq!(heuristic.bsdf_weight(None, || panic!("light pdf needed after a specular bounce")), 1.0);
+            assert_eq!(heuristic.bsdf_weight(Some(1.0), || 3.0), heuristic.weight(1.0, 3.0));
+        }
+    }
+
+    #[test]
+    fn tracks_mean_and_variance() {
+        let values = [0.5, 2.0, 0.25, 1.0, 3.5, 0.0, 1.25];
+        let mut pixel = PixelState::new();
+        for &v in &values {
+            pixel.add(grey(v), &settings());
+        }
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / (n - 1.0);
+        assert_eq!(pixel.count, values.len() as u32);
+        assert!((pixel.mean - mean).abs() < 1e-6);
+        assert!((pixel.m2 / (n - 1.0) - variance).abs() < 1e-5);
+    }
+
+    #[test]
+    fn constant_pixels_converge_at_min_samples() {
+        let adaptive = Settings { noise_threshold: Some(0.01), ..settings() };
+        // black too, thanks to the noise reference floor
+        for &value in &[0.0, 0.3, 20.0] {
+            let mut pixel = PixelState::new();
+            for _ in 1..adaptive.min_samples {
+                pixel.add(grey(value), &adaptive);
+                assert!(!pixel.converged, "converged after {} samples", pixel.count);
+            }
+            pixel.add(grey(value), &adaptive);
+            assert!(pixel.converged);
+        }
+
+        // one sample says nothing about the noise, however few are asked for
+        let adaptive = Settings { min_samples: 1, ..adaptive };
+        let mut pixel = PixelState::new();
+        pixel.add(grey(0.3), &adaptive);
+        assert!(!pixel.converged);
+        pixel.add(grey(0.3), &adaptive);
+        assert!(pixel.converged);
+    }
+
+    #[test]
+    fn noisy_pixels_keep_sampling() {
+        let adaptive = Settings { noise_threshold: Some(0.01), ..settings() };
+        let mut pixel = PixelState::new();
+        for i in 0..1000 {
+            pixel.add(grey((i % 2) as f32), &adaptive);
+            assert!(!pixel.converged, "converged after {} samples", pixel.count);
+        }
+        // without a threshold nothing ever converges
+        let mut pixel = PixelState::new();
+        for _ in 0..100 {
+            pixel.add(grey(1.0), &settings());
+        }
+        assert!(!pixel.converged);
+    }
+
+    // A small lit scene rendered on a pool of the given size.
+    fn render_with(threads: usize, settings: &Settings) -> Render {
+        let white = Texture::constant(0.8, 0.8, 0.8);
+        let light = Texture::constant(4.0, 4.0, 4.0);
+        let floor = Material::lambertian(&white);
+        let glass = Material::dielectric(1.5);
+        let lamp = Material::diffuse_light(&light);
+        let world = vec![
+            Geometry::sphere(Vector3::new(0.0, -100.5, -1.0), 100.0, &floor),
+            Geometry::sphere(Vector3::new(0.0, 0.0, -1.0), 0.5, &glass),
+            Geometry::sphere(Vector3::new(0.0, 2.0, -1.0), 0.5, &lamp)
+        ];
+        let camera = Camera::new(Vector3::new(0.0, 0.5, 2.0), Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 1.0, 0.0),
+            40.0, 1.0, 0.0, 3.0, 0.0, 1.0);
+        // leaked, since the workers of a dropped pool can trip over a bug in
+        // crossbeam-epoch 0.7 on their way out
+        let pool = Box::leak(Box::new(rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap()));
+        pool.install(|| draw(camera, &world, &world, settings, |_| true))
+    }
+
+    #[test]
+    fn renders_the_same_image_on_any_number_of_threads() {
+        let settings = settings();
+        let one = render_with(1, &settings);
+        let four = render_with(4, &settings);
+        assert_eq!(one.framebuffer.pixels, four.framebuffer.pixels);
+        assert_eq!(one.sample_counts, four.sample_counts);
+
+        let reseeded = render_with(4, &Settings { seed: 8, ..settings });
+        assert_ne!(one.framebuffer.pixels, reseeded.framebuffer.pixels);
+    }
+
+    #[test]
+    fn different_seeds_give_different_streams() {
+        let draws = |mut rng: Pcg32| (0..16).map(|_| rng.gen::<u32>()).collect::<Vec<_>>();
+        assert_eq!(draws(Pcg32::for_sample(7, 3, 5)), draws(Pcg32::for_sample(7, 3, 5)));
+        assert_ne!(draws(Pcg32::for_sample(7, 3, 5)), draws(Pcg32::for_sample(8, 3, 5)));
+        assert_ne!(draws(Pcg32::for_sample(7, 3, 5)), draws(Pcg32::for_sample(7, 4, 5)));
+        assert_ne!(draws(Pcg32::for_sample(7, 3, 5)), draws(Pcg32::for_sample(7, 3, 6)));
+        assert_ne!(draws(Pcg32::new(1, 0)), draws(Pcg32::new(2, 0)));
+    }
 }
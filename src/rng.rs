@@ -0,0 +1,69 @@
+use rand::{ Error, RngCore };
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+// A PCG32 generator (XSH RR output). Small and quick to seed, so every camera
+// sample can get its own stream and the image comes out the same no matter
+// which thread rendered which pixel.
+#[derive(Clone)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64
+}
+
+impl Pcg32 {
+    // `stream` picks one of 2^63 independent sequences for the same seed.
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            increment: (stream << 1) | 1
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+        rng
+    }
+
+    // The stream for one sample of one pixel of a render with the given seed.
+    pub fn for_sample(seed: u64, pixel: usize, sample: u32) -> Self {
+        Self::new(mix(seed ^ mix(sample as u64)), pixel as u64)
+    }
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(MULTIPLIER).wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// SplitMix64's finalizer, spreading nearby numbers (consecutive samples, pixels
+// or dimensions) far apart before they become seeds.
+pub fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
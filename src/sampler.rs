@@ -0,0 +1,318 @@
+use rand::Rng;
+
+use crate::rng::{ self, Pcg32 };
+
+// How the numbers behind each sample are picked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    // independent uniform numbers
+    Random,
+    // one jittered sample per cell of a grid over each dimension, with the
+    // cells shuffled differently for every dimension
+    Stratified,
+    // the Halton sequence, shifted randomly in every pixel
+    Halton,
+    // the Sobol sequence with shuffled indices and Owen scrambling
+    Sobol
+}
+
+impl std::str::FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SamplerKind::Random),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler '{}', expected 'random', 'stratified', 'halton' or 'sobol'", s))
+        }
+    }
+}
+
+// Bases for the Halton dimensions, two per 2D sample. Dimensions past the end
+// of the table fall back to random numbers.
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311
+];
+
+// The numbers for one sample of one pixel. Every request, the pixel position,
+// the lens, the time and then each bounce, takes the next dimension, so as long
+// as they are made in the same order the same decision always gets the same
+// dimension of the sequence.
+pub struct Sampler {
+    kind: SamplerKind,
+    // the render seed mixed with the pixel, so pixels get unrelated patterns
+    seed: u64,
+    index: u32,
+    samples: u32,
+    dimension: u32,
+    rng: Pcg32
+}
+
+impl Sampler {
+    // The `index`th of `samples` samples of a pixel.
+    pub fn new(kind: SamplerKind, seed: u64, pixel: usize, index: u32, samples: u32) -> Self {
+        Self {
+            kind,
+            seed: rng::mix(seed ^ rng::mix(pixel as u64)),
+            index,
+            samples: samples.max(index + 1),
+            dimension: 0,
+            rng: Pcg32::for_sample(seed, pixel, index)
+        }
+    }
+
+    pub fn get_1d(&mut self) -> f32 {
+        let (dimension, seed) = self.next_dimension();
+        match self.kind {
+            SamplerKind::Random => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let stratum = permute(self.index, self.samples, seed as u32);
+                (stratum as f32 + self.rng.gen::<f32>()) / self.samples as f32
+            },
+            SamplerKind::Halton => match PRIMES.get(2 * dimension as usize) {
+                Some(&base) => shift(radical_inverse(self.index, base), seed as u32),
+                None => self.rng.gen()
+            },
+            SamplerKind::Sobol => {
+                let index = nested_uniform_scramble(self.index, seed as u32);
+                to_float(nested_uniform_scramble(index.reverse_bits(), (seed >> 32) as u32))
+            }
+        }
+    }
+
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        let (dimension, seed) = self.next_dimension();
+        match self.kind {
+            SamplerKind::Random => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => {
+                // as square a grid as will hold every sample
+                let columns = (self.samples as f32).sqrt().ceil() as u32;
+                let rows = self.samples.div_ceil(columns);
+                let cell = permute(self.index, columns * rows, seed as u32);
+                (
+                    ((cell % columns) as f32 + self.rng.gen::<f32>()) / columns as f32,
+                    ((cell / columns) as f32 + self.rng.gen::<f32>()) / rows as f32
+                )
+            },
+            SamplerKind::Halton => match PRIMES.get(2 * dimension as usize..2 * dimension as usize + 2) {
+                Some(&[base_x, base_y]) => (
+                    shift(radical_inverse(self.index, base_x), seed as u32),
+                    shift(radical_inverse(self.index, base_y), (seed >> 32) as u32)
+                ),
+                _ => (self.rng.gen(), self.rng.gen())
+            },
+            SamplerKind::Sobol => {
+                // the first two Sobol dimensions form a (0, 2) sequence, shuffled
+                // differently for every 2D sample so that they don't correlate
+                let index = nested_uniform_scramble(self.index, seed as u32);
+                let scramble = rng::mix(seed);
+                (
+                    to_float(nested_uniform_scramble(index.reverse_bits(), scramble as u32)),
+                    to_float(nested_uniform_scramble(sobol_second(index), (scramble >> 32) as u32))
+                )
+            }
+        }
+    }
+
+    fn next_dimension(&mut self) -> (u32, u64) {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        (dimension, rng::mix(self.seed ^ dimension as u64))
+    }
+}
+
+fn to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+// Moves a point by a fixed random offset, wrapping around at 1.
+fn shift(x: f32, seed: u32) -> f32 {
+    let offset = to_float(seed);
+    let shifted = x + offset;
+    if shifted >= 1.0 { shifted - 1.0 } else { shifted }
+}
+
+// `index` with its digits in `base` mirrored around the decimal point.
+fn radical_inverse(mut index: u32, base: u32) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut reversed = 0u64;
+    let mut inverse_base_n = 1.0;
+    while index > 0 {
+        reversed = reversed * base as u64 + (index % base) as u64;
+        inverse_base_n *= inverse_base;
+        index /= base;
+    }
+    ((reversed as f64 * inverse_base_n) as f32).min(1.0 - f32::EPSILON / 2.0)
+}
+
+// The second Sobol dimension, whose direction numbers follow from the
+// polynomial x + 1.
+fn sobol_second(index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1u32 << 31;
+    let mut bits = index;
+    while bits > 0 {
+        if bits & 1 == 1 {
+            result ^= direction;
+        }
+        direction ^= direction >> 1;
+        bits >>= 1;
+    }
+    result
+}
+
+// A hash that only lets bits affect higher bits, which is what Owen scrambling
+// needs once the bits are reversed (Laine and Karras, with constants from
+// Vegdahl).
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+// Owen scrambling of a 32-bit fixed point number, following Burley's "Practical
+// Hash-based Owen Scrambling".
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Element `i` of a random permutation of 0..n chosen by `seed`, without
+// building the permutation (Kensler's "Correlated Multi-Jittered Sampling").
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut mask = n - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    // hash within the next power of two until the result lands inside 0..n
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [SamplerKind::Random, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol];
+
+    // The strata that the `dimension`th 1D or 2D sample of every one of
+    // `samples` samples of a pixel lands in, on a `columns` by `rows` grid.
+    fn strata(kind: SamplerKind, samples: u32, dimension: u32, columns: u32, rows: u32) -> Vec<u32> {
+        let mut seen = vec![0; (columns * rows) as usize];
+        for index in 0..samples {
+            let mut sampler = Sampler::new(kind, 11, 5, index, samples);
+            for _ in 0..dimension {
+                sampler.get_2d();
+            }
+            let (x, y) = if rows == 1 { (sampler.get_1d(), 0.0) } else { sampler.get_2d() };
+            let cell = (y * rows as f32) as u32 * columns + (x * columns as f32) as u32;
+            seen[cell as usize] += 1;
+        }
+        seen
+    }
+
+    #[test]
+    fn samples_stay_in_the_unit_interval() {
+        for &kind in &KINDS {
+            for pixel in 0..8 {
+                for index in 0..64 {
+                    let mut sampler = Sampler::new(kind, 3, pixel, index, 64);
+                    // enough dimensions to run past the Halton table
+                    for _ in 0..40 {
+                        let x = sampler.get_1d();
+                        let (y, z) = sampler.get_2d();
+                        for &v in &[x, y, z] {
+                            assert!((0.0..1.0).contains(&v), "{:?} gave {}", kind, v);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn puts_one_sample_in_every_stratum() {
+        for &kind in &[SamplerKind::Stratified, SamplerKind::Sobol] {
+            for &samples in &[1, 2, 4, 16, 64, 256] {
+                for dimension in 0..4 {
+                    assert!(strata(kind, samples, dimension, samples, 1).iter().all(|&n| n == 1),
+                            "{:?} with {} samples", kind, samples);
+                }
+            }
+            for &(samples, side) in &[(4, 2), (16, 4), (64, 8), (256, 16)] {
+                for dimension in 0..4 {
+                    assert!(strata(kind, samples, dimension, side, side).iter().all(|&n| n == 1),
+                            "{:?} with {} samples", kind, samples);
+                }
+            }
+        }
+
+        // every elementary interval of the (0, 2) sequence, not just the square ones
+        for dimension in 0..4 {
+            for columns in &[1, 2, 4, 8, 16, 32, 64] {
+                assert!(strata(SamplerKind::Sobol, 64, dimension, *columns, 64 / columns).iter().all(|&n| n == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn owen_scrambling_permutes_the_leading_bits() {
+        for seed in 0..16 {
+            let seed = rng::mix(seed) as u32;
+            for &bits in &[1, 4, 8, 12] {
+                let mut seen = vec![false; 1 << bits];
+                for i in 0..1u32 << bits {
+                    let scrambled = nested_uniform_scramble(i << (32 - bits), seed) >> (32 - bits);
+                    assert!(!seen[scrambled as usize]);
+                    seen[scrambled as usize] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn permutes_any_count() {
+        for seed in 0..16 {
+            let seed = rng::mix(seed) as u32;
+            for &n in &[1, 2, 3, 7, 16, 100, 1000] {
+                let mut seen = vec![false; n as usize];
+                for i in 0..n {
+                    let j = permute(i, n, seed);
+                    assert!(!seen[j as usize]);
+                    seen[j as usize] = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,842 @@
+// Declarative scene files.
+//
+// A scene is a sequence of items, each followed by a block of `key = value`
+// fields. Values are numbers, "strings", names of earlier items, or
+// [lists, of, values]. Textures and materials are named so that later items
+// can refer to them:
+//
+//     camera { look_from = [278, 278, -800], look_at = [278, 278, 0], vfov = 40 }
+//
+//     texture white = constant { color = [0.73, 0.73, 0.73] }
+//     texture mars = image { file = "img/2k_mars.jpg" }
+//     texture bumps = image { file = "img/bumps.png", linear = true }
+//     material wall = lambertian { albedo = white }
+//     material glass = dielectric { ref_idx = 1.5 }
+//     material flint = dielectric { cauchy_a = 1.67, cauchy_b = 0.0074 }
+//     material bk7 = dielectric { sellmeier_b = [1.0396, 0.2318, 1.0105], sellmeier_c = [0.0060, 0.0200, 103.56] }
+//
+//     sphere { center = [128, 50, 128], radius = 50, material = glass }
+//     mesh { vertices = [0, 0, 0, 1, 0, 0, 0, 1, 0], indices = [0, 1, 2], material = wall }
+//     obj { file = "bunny.obj", material = wall }
+//
+//     material smoke = isotropic { albedo = [0.8, 0.8, 0.8] }
+//     sphere { center = [278, 100, 278], radius = 100, material = smoke, density = 0.01 }
+//
+//     tonemap { operator = "aces", exposure = -0.5 }
+//
+// A `density` on any shape fills it with fog or smoke instead, which scatters
+// with the shape's material, normally an isotropic one.
+//
+// Anywhere a texture is expected, a color list can be given instead. File paths
+// are relative to the scene file. `#` starts a comment.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use cgmath::{ InnerSpace, Vector3 };
+use rand::RngCore;
+use typed_arena::Arena;
+
+use crate::camera::Camera;
+use crate::hitable::Geometry;
+use crate::material::{ Dispersion, Material };
+use crate::mesh::Mesh;
+use crate::obj;
+use crate::rng::Pcg32;
+use crate::texture::Texture;
+use crate::tonemap::{ self, Operator, ToneMap };
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    Syntax { line: usize, column: usize, message: String }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "{}", e),
+            SceneError::Syntax { line, column, message } => write!(f, "{}:{}: {}", line, column, message)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Clone, Copy, Debug)]
+struct Position {
+    line: usize,
+    column: usize
+}
+
+impl Position {
+    fn error<T>(self, message: String) -> Result<T, SceneError> {
+        Err(SceneError::Syntax { line: self.line, column: self.column, message })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f32),
+    Str(String),
+    Symbol(char)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "'{}'", s),
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Str(s) => write!(f, "string \"{}\"", s),
+            Token::Symbol(c) => write!(f, "'{}'", c)
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Position)>, SceneError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut pos = Position { line: 1, column: 1 };
+
+    let advance = |c: char, pos: &mut Position| {
+        if c == '\n' {
+            pos.line += 1;
+            pos.column = 1;
+        } else {
+            pos.column += 1;
+        }
+    };
+
+    while let Some(&c) = chars.peek() {
+        let start = pos;
+        if c.is_whitespace() {
+            chars.next();
+            advance(c, &mut pos);
+        } else if c == '#' {
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                chars.next();
+                advance(c, &mut pos);
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+                advance(c, &mut pos);
+            }
+            tokens.push((Token::Ident(ident), start));
+        } else if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                let exponent_sign = (c == '-' || c == '+') && number.ends_with(['e', 'E']);
+                if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign || number.is_empty()) {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+                advance(c, &mut pos);
+            }
+            match number.parse::<f32>() {
+                Ok(n) => tokens.push((Token::Number(n), start)),
+                Err(_) => return start.error(format!("invalid number '{}'", number))
+            }
+        } else if c == '"' {
+            chars.next();
+            advance(c, &mut pos);
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => {
+                        advance('"', &mut pos);
+                        break;
+                    },
+                    Some('\\') => {
+                        advance('\\', &mut pos);
+                        match chars.next() {
+                            Some(escaped) if escaped == '"' || escaped == '\\' => {
+                                advance(escaped, &mut pos);
+                                string.push(escaped);
+                            },
+                            _ => return pos.error(String::from("only \\\" and \\\\ escapes are allowed in strings"))
+                        }
+                    },
+                    Some('\n') | None => return start.error(String::from("unterminated string")),
+                    Some(c) => {
+                        advance(c, &mut pos);
+                        string.push(c);
+                    }
+                }
+            }
+            tokens.push((Token::Str(string), start));
+        } else if "{}[]=,".contains(c) {
+            chars.next();
+            advance(c, &mut pos);
+            tokens.push((Token::Symbol(c), start));
+        } else {
+            return start.error(format!("unexpected character '{}'", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Value {
+    Number(f32),
+    Str(String),
+    Ident(String),
+    List(Vec<(Value, Position)>)
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "a number",
+            Value::Str(_) => "a string",
+            Value::Ident(_) => "a name",
+            Value::List(_) => "a list"
+        }
+    }
+}
+
+struct Field {
+    value: Value,
+    key_pos: Position,
+    value_pos: Position
+}
+
+// One top-level statement, e.g. `material glass = dielectric { ref_idx = 1.5 }`.
+struct Item {
+    keyword: String,
+    name: Option<String>,
+    kind: Option<String>,
+    pos: Position,
+    fields: Vec<(String, Field)>
+}
+
+struct Parser {
+    tokens: Vec<(Token, Position)>,
+    next: usize,
+    end: Position
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(Token, Position)> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Result<(Token, Position), SceneError> {
+        match self.tokens.get(self.next) {
+            Some(t) => {
+                self.next += 1;
+                Ok(t.clone())
+            },
+            None => self.end.error(String::from("unexpected end of file"))
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<Position, SceneError> {
+        match self.advance()? {
+            (Token::Symbol(c), pos) if c == symbol => Ok(pos),
+            (t, pos) => pos.error(format!("expected '{}', found {}", symbol, t))
+        }
+    }
+
+    fn expect_ident(&mut self, what: &str) -> Result<(String, Position), SceneError> {
+        match self.advance()? {
+            (Token::Ident(s), pos) => Ok((s, pos)),
+            (t, pos) => pos.error(format!("expected {}, found {}", what, t))
+        }
+    }
+
+    fn item(&mut self) -> Result<Item, SceneError> {
+        let (keyword, pos) = self.expect_ident("an item such as camera, texture, material or sphere")?;
+        let (name, kind) = if keyword == "texture" || keyword == "material" {
+            let (name, _) = self.expect_ident(&format!("a {} name", keyword))?;
+            self.expect_symbol('=')?;
+            let (kind, _) = self.expect_ident(&format!("a {} type", keyword))?;
+            (Some(name), Some(kind))
+        } else {
+            (None, None)
+        };
+
+        self.expect_symbol('{')?;
+        let mut fields: Vec<(String, Field)> = Vec::new();
+        loop {
+            match self.peek() {
+                Some((Token::Symbol('}'), _)) => {
+                    self.next += 1;
+                    break;
+                },
+                Some((Token::Symbol(','), _)) => {
+                    self.next += 1;
+                },
+                _ => {
+                    let (key, key_pos) = self.expect_ident("a field name or '}'")?;
+                    if fields.iter().any(|(k, _)| *k == key) {
+                        return key_pos.error(format!("field '{}' is given more than once", key));
+                    }
+                    self.expect_symbol('=')?;
+                    let (value, value_pos) = self.value()?;
+                    fields.push((key, Field { value, key_pos, value_pos }));
+                }
+            }
+        }
+
+        Ok(Item { keyword, name, kind, pos, fields })
+    }
+
+    fn value(&mut self) -> Result<(Value, Position), SceneError> {
+        match self.advance()? {
+            (Token::Number(n), pos) => Ok((Value::Number(n), pos)),
+            (Token::Str(s), pos) => Ok((Value::Str(s), pos)),
+            (Token::Ident(s), pos) => Ok((Value::Ident(s), pos)),
+            (Token::Symbol('['), pos) => {
+                let mut items = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some((Token::Symbol(']'), _)) => {
+                            self.next += 1;
+                            break;
+                        },
+                        Some((Token::Symbol(','), _)) if !items.is_empty() => {
+                            self.next += 1;
+                        },
+                        _ => items.push(self.value()?)
+                    }
+                }
+                Ok((Value::List(items), pos))
+            },
+            (t, pos) => pos.error(format!("expected a value, found {}", t))
+        }
+    }
+}
+
+// Field accessors for one item, which make sure every field is used exactly once.
+struct Fields<'i> {
+    item: &'i Item,
+    used: Vec<bool>
+}
+
+impl<'i> Fields<'i> {
+    fn new(item: &'i Item) -> Self {
+        Self {
+            item,
+            used: vec![false; item.fields.len()]
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&'i Field> {
+        let index = self.item.fields.iter().position(|(k, _)| k == key)?;
+        self.used[index] = true;
+        Some(&self.item.fields[index].1)
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.item.fields.iter().any(|(k, _)| k == key)
+    }
+
+    fn require(&mut self, key: &str) -> Result<&'i Field, SceneError> {
+        match self.get(key) {
+            Some(f) => Ok(f),
+            None => self.item.pos.error(format!("{} is missing field '{}'", self.item.keyword, key))
+        }
+    }
+
+    fn number(&mut self, key: &str, default: Option<f32>) -> Result<f32, SceneError> {
+        match (self.get(key), default) {
+            (Some(field), _) => number(&field.value, field.value_pos),
+            (None, Some(d)) => Ok(d),
+            (None, None) => self.require(key).map(|_| 0.0)
+        }
+    }
+
+    fn vector(&mut self, key: &str, default: Option<Vector3<f32>>) -> Result<Vector3<f32>, SceneError> {
+        match (self.get(key), default) {
+            (Some(field), _) => vector(&field.value, field.value_pos),
+            (None, Some(d)) => Ok(d),
+            (None, None) => self.require(key).map(|_| Vector3::new(0.0, 0.0, 0.0))
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Result<&'i str, SceneError> {
+        let field = self.require(key)?;
+        match &field.value {
+            Value::Str(s) => Ok(s),
+            v => field.value_pos.error(format!("expected a string, found {}", v.describe()))
+        }
+    }
+
+    fn flag(&mut self, key: &str, default: bool) -> Result<bool, SceneError> {
+        match self.get(key) {
+            Some(field) => match &field.value {
+                Value::Ident(s) if s == "true" => Ok(true),
+                Value::Ident(s) if s == "false" => Ok(false),
+                v => field.value_pos.error(format!("expected true or false, found {}", v.describe()))
+            },
+            None => Ok(default)
+        }
+    }
+
+    fn numbers(&mut self, key: &str) -> Result<Option<Vec<f32>>, SceneError> {
+        match self.get(key) {
+            Some(field) => match &field.value {
+                Value::List(items) => items.iter().map(|(v, pos)| number(v, *pos)).collect::<Result<Vec<f32>, _>>().map(Some),
+                v => field.value_pos.error(format!("expected a list of numbers, found {}", v.describe()))
+            },
+            None => Ok(None)
+        }
+    }
+
+    // reports the first field that no accessor asked for, which is most likely a typo
+    fn finish(self) -> Result<(), SceneError> {
+        match self.used.iter().position(|used| !used) {
+            Some(index) => {
+                let (key, field) = &self.item.fields[index];
+                field.key_pos.error(format!("unknown field '{}' for {}", key, self.item.kind.as_ref().unwrap_or(&self.item.keyword)))
+            },
+            None => Ok(())
+        }
+    }
+}
+
+fn number(value: &Value, pos: Position) -> Result<f32, SceneError> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        v => pos.error(format!("expected a number, found {}", v.describe()))
+    }
+}
+
+fn vector(value: &Value, pos: Position) -> Result<Vector3<f32>, SceneError> {
+    match value {
+        Value::List(items) if items.len() == 3 => Ok(Vector3::new(
+            number(&items[0].0, items[0].1)?,
+            number(&items[1].0, items[1].1)?,
+            number(&items[2].0, items[2].1)?
+        )),
+        Value::List(items) => pos.error(format!("expected a list of 3 numbers, found {} values", items.len())),
+        v => pos.error(format!("expected a list of 3 numbers, found {}", v.describe()))
+    }
+}
+
+pub struct Scene<'a> {
+    pub camera: Camera,
+    pub world: Vec<Geometry<'a>>,
+    // how the image should be tone mapped, if the scene says
+    pub tone_map: Option<ToneMap>
+}
+
+// Reads a scene file, allocating its textures and materials in the given arenas.
+// `aspect` is the output image's width over height, which the camera needs, and
+// `seed` decides the random parts of textures such as noise.
+pub fn load<'a>(
+    path: &Path,
+    aspect: f32,
+    seed: u64,
+    textures: &'a Arena<Texture<'a>>,
+    materials: &'a Arena<Material<'a>>) -> Result<Scene<'a>, SceneError> {
+    let source = fs::read_to_string(path).map_err(SceneError::Io)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    parse(&source, &base_dir, aspect, seed, textures, materials)
+}
+
+struct Builder<'a> {
+    base_dir: PathBuf,
+    rng: Pcg32,
+    textures: &'a Arena<Texture<'a>>,
+    materials: &'a Arena<Material<'a>>,
+    named_textures: HashMap<String, &'a Texture<'a>>,
+    named_materials: HashMap<String, &'a Material<'a>>
+}
+
+impl<'a> Builder<'a> {
+    fn texture_ref(&mut self, fields: &mut Fields, key: &str) -> Result<&'a Texture<'a>, SceneError> {
+        let field = fields.require(key)?;
+        match &field.value {
+            Value::Ident(name) => match self.named_textures.get(name) {
+                Some(t) => Ok(*t),
+                None => field.value_pos.error(format!("unknown texture '{}'", name))
+            },
+            // a color inline is shorthand for an unnamed constant texture
+            Value::List(_) => {
+                let c = vector(&field.value, field.value_pos)?;
+                Ok(self.textures.alloc(Texture::constant(c.x, c.y, c.z)))
+            },
+            v => field.value_pos.error(format!("expected a texture name or color, found {}", v.describe()))
+        }
+    }
+
+    fn material_ref(&mut self, fields: &mut Fields) -> Result<&'a Material<'a>, SceneError> {
+        let field = fields.require("material")?;
+        match &field.value {
+            Value::Ident(name) => match self.named_materials.get(name) {
+                Some(m) => Ok(*m),
+                None => field.value_pos.error(format!("unknown material '{}'", name))
+            },
+            v => field.value_pos.error(format!("expected a material name, found {}", v.describe()))
+        }
+    }
+
+    fn texture(&mut self, kind: &str, item: &Item, fields: &mut Fields) -> Result<Texture<'a>, SceneError> {
+        match kind {
+            "constant" => {
+                let c = fields.vector("color", None)?;
+                Ok(Texture::constant(c.x, c.y, c.z))
+            },
+            "checker" => {
+                let even = self.texture_ref(fields, "even")?;
+                let odd = self.texture_ref(fields, "odd")?;
+                Ok(Texture::checker(even, odd))
+            },
+            "noise" => Ok(Texture::noise(fields.number("scale", Some(1.0))?, &mut self.rng)),
+            "image" => {
+                let file_field = fields.require("file")?;
+                let file = self.base_dir.join(fields.string("file")?);
+                match Texture::load_image(&file.to_string_lossy(), fields.flag("linear", false)?) {
+                    Ok(texture) => Ok(texture),
+                    Err(e) => file_field.value_pos.error(format!("could not load {}: {}", file.display(), e))
+                }
+            },
+            _ => item.pos.error(format!("unknown texture type '{}', expected constant, checker, noise or image", kind))
+        }
+    }
+
+    fn material(&mut self, kind: &str, item: &Item, fields: &mut Fields) -> Result<Material<'a>, SceneError> {
+        match kind {
+            "lambertian" => Ok(Material::lambertian(self.texture_ref(fields, "albedo")?)),
+            "metal" => {
+                let albedo = self.texture_ref(fields, "albedo")?;
+                Ok(Material::metal(albedo, fields.number("fuzz", Some(0.0))?))
+            },
+            "dielectric" => {
+                if fields.has("cauchy_a") || fields.has("cauchy_b") {
+                    let a = fields.number("cauchy_a", None)?;
+                    let b = fields.number("cauchy_b", None)?;
+                    Ok(Material::dispersive_dielectric(Dispersion::Cauchy { a, b }))
+                } else if fields.has("sellmeier_b") || fields.has("sellmeier_c") {
+                    let b = fields.vector("sellmeier_b", None)?;
+                    let c = fields.vector("sellmeier_c", None)?;
+                    Ok(Material::dispersive_dielectric(Dispersion::Sellmeier { b: b.into(), c: c.into() }))
+                } else {
+                    Ok(Material::dielectric(fields.number("ref_idx", None)?))
+                }
+            },
+            "diffuse_light" => Ok(Material::diffuse_light(self.texture_ref(fields, "emit")?)),
+            "isotropic" => Ok(Material::isotropic(self.texture_ref(fields, "albedo")?)),
+            _ => item.pos.error(format!("unknown material type '{}', expected lambertian, metal, dielectric, diffuse_light or isotropic", kind))
+        }
+    }
+
+    fn geometry(&mut self, item: &Item, fields: &mut Fields, world: &mut Vec<Geometry<'a>>) -> Result<(), SceneError> {
+        let density = if fields.has("density") {
+            let field = fields.require("density")?;
+            match fields.number("density", None)? {
+                d if d > 0.0 => Some(d),
+                _ => return field.value_pos.error(String::from("density must be greater than 0"))
+            }
+        } else {
+            None
+        };
+        let first = world.len();
+
+        match &item.keyword[..] {
+            "sphere" => {
+                let center = fields.vector("center", None)?;
+                let radius = fields.number("radius", None)?;
+                world.push(Geometry::sphere(center, radius, self.material_ref(fields)?));
+            },
+            "moving_sphere" => {
+                let center0 = fields.vector("center0", None)?;
+                let center1 = fields.vector("center1", None)?;
+                let time0 = fields.number("time0", Some(0.0))?;
+                let time1 = fields.number("time1", Some(1.0))?;
+                let radius = fields.number("radius", None)?;
+                world.push(Geometry::moving_sphere(center0, center1, time0, time1, radius, self.material_ref(fields)?));
+            },
+            "mesh" => {
+                fields.require("vertices")?;
+                let vertices = fields.numbers("vertices")?.unwrap_or_default();
+                let indices_field = fields.require("indices")?;
+                let indices = fields.numbers("indices")?.unwrap_or_default();
+                if vertices.len() % 3 != 0 {
+                    return item.pos.error(String::from("mesh vertices must be a multiple of 3 numbers"));
+                }
+                if indices.len() % 3 != 0 {
+                    return indices_field.value_pos.error(String::from("mesh indices must be a multiple of 3 numbers"));
+                }
+                let vertex_count = vertices.len() / 3;
+                let indices = indices.iter().map(|&i| {
+                    if i < 0.0 || i.fract() != 0.0 || i as usize >= vertex_count {
+                        indices_field.value_pos.error(format!("{} is not a valid index for {} vertices", i, vertex_count))
+                    } else {
+                        Ok(i as usize)
+                    }
+                }).collect::<Result<Vec<usize>, _>>()?;
+
+                let normals = fields.numbers("normals")?;
+                let uvs = fields.numbers("uvs")?;
+                let mut mesh = Mesh::new(vertices, indices, self.material_ref(fields)?);
+                if let Some(normals) = normals {
+                    if normals.len() != 3 * vertex_count {
+                        return item.pos.error(String::from("mesh needs 3 normal components per vertex"));
+                    }
+                    mesh = mesh.with_normals(normals);
+                }
+                if let Some(uvs) = uvs {
+                    if uvs.len() != 2 * vertex_count {
+                        return item.pos.error(String::from("mesh needs 2 texture coordinates per vertex"));
+                    }
+                    mesh = mesh.with_uvs(uvs);
+                }
+                world.push(Geometry::Mesh(mesh));
+            },
+            "obj" => {
+                let file_field = fields.require("file")?;
+                let file = self.base_dir.join(fields.string("file")?);
+                let material = self.material_ref(fields)?;
+                match obj::load_geometry(&file, material, self.textures, self.materials) {
+                    Ok(geometry) => world.extend(geometry),
+                    Err(e) => return file_field.value_pos.error(format!("could not load {}: {}", file.display(), e))
+                }
+            },
+            other => return item.pos.error(format!("unknown item '{}'", other))
+        }
+
+        // turn the shapes just added into volumes
+        if let Some(density) = density {
+            let shapes: Vec<Geometry<'a>> = world.drain(first..).collect();
+            for shape in shapes {
+                let phase = shape.material();
+                if let Material::DiffuseLight(_) = phase {
+                    return item.pos.error(String::from("a volume can't be a light"));
+                }
+                world.push(Geometry::constant_medium(shape, density, phase, self.rng.next_u64()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<'a>(
+    source: &str,
+    base_dir: &Path,
+    aspect: f32,
+    seed: u64,
+    textures: &'a Arena<Texture<'a>>,
+    materials: &'a Arena<Material<'a>>) -> Result<Scene<'a>, SceneError> {
+    let tokens = tokenize(source)?;
+    let end = Position {
+        line: source.lines().count().max(1),
+        column: source.lines().last().map_or(1, |l| l.chars().count() + 1)
+    };
+    let mut parser = Parser { tokens, next: 0, end };
+
+    let mut builder = Builder {
+        base_dir: base_dir.to_path_buf(),
+        rng: Pcg32::new(seed, 0),
+        textures,
+        materials,
+        named_textures: HashMap::new(),
+        named_materials: HashMap::new()
+    };
+    let mut camera = None;
+    let mut tone_map = None;
+    let mut world = Vec::new();
+
+    while parser.peek().is_some() {
+        let item = parser.item()?;
+        let mut fields = Fields::new(&item);
+
+        match (&item.keyword[..], &item.name, &item.kind) {
+            ("camera", _, _) => {
+                if camera.is_some() {
+                    return item.pos.error(String::from("the scene already has a camera"));
+                }
+                let look_from = fields.vector("look_from", None)?;
+                let look_at = fields.vector("look_at", None)?;
+                let up = fields.vector("up", Some(Vector3::new(0.0, 1.0, 0.0)))?;
+                let vfov = fields.number("vfov", Some(40.0))?;
+                let aperture = fields.number("aperture", Some(0.0))?;
+                let focus_dist = fields.number("focus_dist", Some((look_from - look_at).magnitude()))?;
+                let time0 = fields.number("time0", Some(0.0))?;
+                let time1 = fields.number("time1", Some(1.0))?;
+                camera = Some(Camera::new(look_from, look_at, up, vfov, aspect, aperture, focus_dist, time0, time1));
+            },
+            ("tonemap", _, _) => {
+                if tone_map.is_some() {
+                    return item.pos.error(String::from("the scene already has a tonemap"));
+                }
+                let operator = match fields.string("operator")?.parse::<Operator>() {
+                    Ok(operator) => operator,
+                    Err(e) => return item.pos.error(e)
+                };
+                let exposure = fields.number("exposure", Some(0.0))?;
+                let white = match fields.get("white") {
+                    Some(field) => match tonemap::white_point(number(&field.value, field.value_pos)?) {
+                        Ok(w) => Some(w),
+                        Err(e) => return field.value_pos.error(e)
+                    },
+                    None => None
+                };
+                tone_map = Some(ToneMap { operator, exposure, white });
+            },
+            ("texture", Some(name), Some(kind)) => {
+                if builder.named_textures.contains_key(name) {
+                    return item.pos.error(format!("texture '{}' is already defined", name));
+                }
+                let texture = builder.texture(kind, &item, &mut fields)?;
+                builder.named_textures.insert(name.clone(), textures.alloc(texture));
+            },
+            ("material", Some(name), Some(kind)) => {
+                if builder.named_materials.contains_key(name) {
+                    return item.pos.error(format!("material '{}' is already defined", name));
+                }
+                let material = builder.material(kind, &item, &mut fields)?;
+                builder.named_materials.insert(name.clone(), materials.alloc(material));
+            },
+            _ => builder.geometry(&item, &mut fields, &mut world)?
+        }
+
+        fields.finish()?;
+    }
+
+    match camera {
+        Some(camera) => Ok(Scene { camera, world, tone_map }),
+        None => end.error(String::from("the scene has no camera"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "camera { look_from = [0, 0, -10], look_at = [0, 0, 0] }\n";
+
+    // parses `source` after a camera, and hands the world to `check`
+    fn with_world(source: &str, check: impl FnOnce(&[Geometry])) {
+        let textures = Arena::new();
+        let materials = Arena::new();
+        let source = format!("{}{}", CAMERA, source);
+        match parse(&source, Path::new(""), 1.0, 0, &textures, &materials) {
+            Ok(scene) => check(&scene.world),
+            Err(e) => panic!("unexpected error {}", e)
+        }
+    }
+
+    // the tone map of a scene with a camera and `source`
+    fn tone_map(source: &str) -> Option<ToneMap> {
+        let textures = Arena::new();
+        let materials = Arena::new();
+        let source = format!("{}{}", CAMERA, source);
+        parse(&source, Path::new(""), 1.0, 0, &textures, &materials).unwrap().tone_map
+    }
+
+    // the line, column and message of the error parsing `source` gives
+    fn parse_error(source: &str) -> (usize, usize, String) {
+        let textures = Arena::new();
+        let materials = Arena::new();
+        match parse(source, Path::new(""), 1.0, 0, &textures, &materials) {
+            Err(SceneError::Syntax { line, column, message }) => (line, column, message),
+            Err(e) => panic!("expected a syntax error, found {}", e),
+            Ok(_) => panic!("expected a syntax error for {:?}", source)
+        }
+    }
+
+    #[test]
+    fn builds_shapes() {
+        let source = "\
+            material red = lambertian { albedo = [1, 0, 0] } # inline color\n\
+            sphere { center = [1, 2, 3], radius = 0.5, material = red }\n\
+            mesh { vertices = [0, 0, 0, 1, 0, 0, 0, 1, 0], indices = [0, 1, 2], material = red }\n";
+        with_world(source, |world| {
+            assert_eq!(world.len(), 2);
+            match &world[0] {
+                Geometry::Sphere(s) => {
+                    assert_eq!(s.center, Vector3::new(1.0, 2.0, 3.0));
+                    assert_eq!(s.radius, 0.5);
+                },
+                _ => panic!("expected a sphere")
+            }
+            match &world[1] {
+                Geometry::Mesh(m) => assert_eq!(m.indices, vec![0, 1, 2]),
+                _ => panic!("expected a mesh")
+            }
+        });
+    }
+
+    #[test]
+    fn fills_shapes_with_density() {
+        let source = "\
+            material fog = isotropic { albedo = [0.8, 0.8, 0.8] }\n\
+            sphere { center = [0, 0, 0], radius = 2, material = fog, density = 0.1 }\n";
+        with_world(source, |world| {
+            assert_eq!(world.len(), 1);
+            match &world[0] {
+                Geometry::ConstantMedium(v) => {
+                    assert_eq!(v.density, 0.1);
+                    assert!(matches!(*v.boundary, Geometry::Sphere(_)));
+                },
+                _ => panic!("expected a volume")
+            }
+        });
+    }
+
+    #[test]
+    fn reads_the_tone_map() {
+        assert!(tone_map("").is_none());
+        let hable = tone_map("tonemap { operator = \"hable\", exposure = -0.5 }").unwrap();
+        assert_eq!(hable.operator, Operator::Hable);
+        assert_eq!(hable.exposure, -0.5);
+        assert_eq!(hable.white, None);
+        assert_eq!(tone_map("tonemap { operator = \"extended-reinhard\", white = 8 }").unwrap().white, Some(8.0));
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert_eq!(parse_error("camera { look_from = [0, 0, 1], look_at = [0, 0, 0], fov = 40 }"),
+            (1, 54, String::from("unknown field 'fov' for camera")));
+        assert_eq!(parse_error("camera {\n  look_at = [0, 0, 0]\n}"),
+            (1, 1, String::from("camera is missing field 'look_from'")));
+        assert_eq!(parse_error("camera { look_from = [0, 0], look_at = [0, 0, 0] }"),
+            (1, 22, String::from("expected a list of 3 numbers, found 2 values")));
+        assert_eq!(parse_error("camera { look_from = [0, 0, 1], look_at = [0, 0, 0] }\nsphere { radius = 1 }"),
+            (2, 1, String::from("sphere is missing field 'center'")));
+        assert_eq!(parse_error("camera { look_from = \"up\n"),
+            (1, 22, String::from("unterminated string")));
+        assert_eq!(parse_error(""), (1, 1, String::from("the scene has no camera")));
+    }
+
+    #[test]
+    fn reports_invalid_items() {
+        let error = |source: &str| parse_error(&format!("{}{}", CAMERA, source));
+        assert_eq!(error("material m = metal { albedo = [1, 1, 1] }\nmaterial m = metal { albedo = [1, 1, 1] }"),
+            (3, 1, String::from("material 'm' is already defined")));
+        assert_eq!(error("sphere { center = [0, 0, 0], radius = 1, material = missing }"),
+            (2, 53, String::from("unknown material 'missing'")));
+        assert_eq!(error("mesh { vertices = [0, 0, 0], indices = [0, 0, 1], material = m }"),
+            (2, 40, String::from("1 is not a valid index for 1 vertices")));
+        let (line, column, message) = error("texture t = image { file = \"no-such-file.png\" }");
+        assert_eq!((line, column), (2, 28));
+        assert!(message.starts_with("could not load no-such-file.png: "));
+        assert_eq!(error("material m = lambertian { albedo = [1, 1, 1] }\nsphere { center = [0, 0, 0], radius = 1, material = m, density = 0 }"),
+            (3, 66, String::from("density must be greater than 0")));
+        assert_eq!(error("material m = diffuse_light { emit = [1, 1, 1] }\nsphere { center = [0, 0, 0], radius = 1, material = m, density = 1 }"),
+            (3, 1, String::from("a volume can't be a light")));
+        assert_eq!(error("tonemap { operator = \"aces\" }\ntonemap { operator = \"aces\" }"),
+            (3, 1, String::from("the scene already has a tonemap")));
+        assert_eq!(error("tonemap { operator = \"hable\", white = -1 }"),
+            (2, 39, String::from("white must be greater than 0")));
+        assert_eq!(error("tonemap { operator = \"hable\", white = 0 }").2, "white must be greater than 0");
+        assert_eq!(error("tonemap { operator = \"filmic\" }").0, 2);
+    }
+}
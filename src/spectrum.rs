@@ -0,0 +1,243 @@
+use cgmath::{ Vector3, Vector4 };
+
+// Wavelengths sampled per path. They are spread evenly over the range from one
+// random "hero" wavelength, so each path carries a bit of the whole spectrum.
+pub const SAMPLES: usize = 4;
+
+const MIN_WAVELENGTH: f32 = 360.0;
+const MAX_WAVELENGTH: f32 = 830.0;
+
+// A few wavelengths along with the density each was picked with.
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    pub lambda: [f32; SAMPLES],
+    pdf: [f32; SAMPLES]
+}
+
+impl Wavelengths {
+    // Picks wavelengths, in nanometres, in proportion to how visible they are,
+    // using the distribution from pbrt-v4.
+    pub fn sample(u: f32) -> Self {
+        let mut lambda = [0.0; SAMPLES];
+        let mut pdf = [0.0; SAMPLES];
+        for i in 0..SAMPLES {
+            let ui = (u + i as f32 / SAMPLES as f32).fract();
+            let l = 538.0 - 138.888_89 * (0.856_910_6 - 1.827_502 * ui).atanh();
+            lambda[i] = l.clamp(MIN_WAVELENGTH, MAX_WAVELENGTH);
+            pdf[i] = if (MIN_WAVELENGTH..=MAX_WAVELENGTH).contains(&l) {
+                0.003_939_804 / (0.0072 * (l - 538.0)).cosh().powi(2)
+            } else {
+                0.0
+            };
+        }
+        Self { lambda, pdf }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    // Drops all but the hero wavelength, for when the path has split up by
+    // wavelength (dispersion) and only the hero's direction was followed.
+    pub fn terminate_secondary(&mut self) {
+        if self.is_secondary_terminated() {
+            return;
+        }
+        for pdf in &mut self.pdf[1..] {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= SAMPLES as f32;
+    }
+
+    pub fn is_secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&pdf| pdf == 0.0)
+    }
+
+    // Converts values at these wavelengths to linear sRGB, through the CIE XYZ
+    // color matching functions.
+    pub fn to_rgb(self, values: Vector4<f32>) -> Vector3<f32> {
+        let mut xyz = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0..SAMPLES {
+            if self.pdf[i] > 0.0 {
+                xyz += color_matching(self.lambda[i]) * (values[i] / self.pdf[i]);
+            }
+        }
+        xyz_to_rgb(xyz / SAMPLES as f32)
+    }
+}
+
+// How the colors along a path are carried: as red, green and blue (the fourth
+// channel is unused), or as the values at a few sampled wavelengths.
+pub enum Channels {
+    Rgb,
+    Spectral(Wavelengths)
+}
+
+impl Channels {
+    // A reflectance, such as an albedo or a BSDF value.
+    pub fn reflectance(&self, rgb: Vector3<f32>) -> Vector4<f32> {
+        match self {
+            Channels::Rgb => rgb.extend(0.0),
+            Channels::Spectral(wavelengths) => wavelengths.lambda.map(|l| upsample(rgb, l)).into()
+        }
+    }
+
+    // Emitted light, whose spectrum is the reflectance shape lit by D65 so that
+    // white lights stay white once converted back to RGB.
+    pub fn emission(&self, rgb: Vector3<f32>) -> Vector4<f32> {
+        match self {
+            Channels::Rgb => rgb.extend(0.0),
+            Channels::Spectral(wavelengths) => wavelengths.lambda.map(|l| upsample(rgb, l) * d65(l) / D65_Y).into()
+        }
+    }
+
+    // The largest of the values that still count, leaving out the wavelengths
+    // a dispersive bounce dropped, whatever they were left holding.
+    pub fn max_live(&self, values: Vector4<f32>) -> f32 {
+        match self {
+            Channels::Spectral(wavelengths) if wavelengths.is_secondary_terminated() => values.x,
+            _ => values.x.max(values.y).max(values.z).max(values.w)
+        }
+    }
+
+    pub fn to_rgb(&self, values: Vector4<f32>) -> Vector3<f32> {
+        match self {
+            Channels::Rgb => values.truncate(),
+            Channels::Spectral(wavelengths) => wavelengths.to_rgb(values)
+        }
+    }
+
+    // The wavelength that decides directions that depend on it, if there is one.
+    pub fn wavelength(&self) -> Option<f32> {
+        match self {
+            Channels::Rgb => None,
+            Channels::Spectral(wavelengths) => Some(wavelengths.hero())
+        }
+    }
+}
+
+// Smits' "An RGB to Spectrum Conversion for Reflectances": spectra for white
+// and the primary and secondary colors, in 10 bins from 380 to 720 nm, mixed
+// to match an RGB color.
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// The value at `lambda` of a spectrum with the given RGB color.
+pub fn upsample(rgb: Vector3<f32>, lambda: f32) -> f32 {
+    let bin = (((lambda - 380.0) / 34.0) as isize).clamp(0, 9) as usize;
+    let (r, g, b) = (rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0));
+    let white = SMITS_WHITE[bin];
+    if r <= g && r <= b {
+        r * white + if g <= b {
+            (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        g * white + if r <= b {
+            (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        b * white + if r <= g {
+            (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+// The CIE D65 illuminant from 380 to 780 nm in 10 nm steps.
+const D65: [f32; 41] = [
+    49.98, 54.65, 82.75, 91.49, 93.43, 86.68, 104.86, 117.01, 117.81, 114.86,
+    115.92, 108.81, 109.35, 107.80, 104.79, 107.69, 104.41, 104.05, 100.00, 96.33,
+    95.79, 88.69, 90.01, 89.60, 87.70, 83.29, 83.70, 80.03, 80.21, 82.28,
+    78.28, 69.72, 71.61, 74.35, 61.60, 69.89, 75.09, 63.59, 46.42, 66.81,
+    63.38
+];
+
+// The integral of D65 times the Y matching function, which scales D65 to a
+// luminance of 1.
+const D65_Y: f32 = 10_569.5;
+
+fn d65(lambda: f32) -> f32 {
+    let x = ((lambda - 380.0) / 10.0).clamp(0.0, 40.0);
+    let i = (x as usize).min(39);
+    let t = x - i as f32;
+    D65[i] * (1.0 - t) + D65[i + 1] * t
+}
+
+// Wyman, Sloan and Shirley's multi-lobe fit of the CIE 1931 color matching
+// functions.
+fn color_matching(lambda: f32) -> Vector3<f32> {
+    let lobe = |mu: f32, sigma_below: f32, sigma_above: f32| {
+        let t = (lambda - mu) / if lambda < mu { sigma_below } else { sigma_above };
+        (-0.5 * t * t).exp()
+    };
+    Vector3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8)
+    )
+}
+
+fn xyz_to_rgb(xyz: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(
+        3.240_454 * xyz.x - 1.537_139 * xyz.y - 0.498_531 * xyz.z,
+        -0.969_266 * xyz.x + 1.876_011 * xyz.y + 0.041_556 * xyz.z,
+        0.055_643 * xyz.x - 0.204_026 * xyz.y + 1.057_225 * xyz.z
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    fn white() -> Vector3<f32> {
+        Vector3::new(1.0, 1.0, 1.0)
+    }
+
+    #[test]
+    fn white_under_d65_comes_back_white() {
+        let mut xyz = Vector3::new(0.0, 0.0, 0.0);
+        let step = 0.5;
+        let mut lambda = MIN_WAVELENGTH;
+        while lambda < MAX_WAVELENGTH {
+            let l = lambda + 0.5 * step;
+            xyz += color_matching(l) * (upsample(white(), l) * d65(l) / D65_Y * step);
+            lambda += step;
+        }
+        let rgb = xyz_to_rgb(xyz);
+        assert!((rgb - white()).magnitude() < 0.005, "white came back as {:?}", rgb);
+    }
+
+    #[test]
+    fn sampled_white_light_averages_to_white() {
+        let passes = 4096;
+        let mut rgb = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0..passes {
+            let channels = Channels::Spectral(Wavelengths::sample((i as f32 + 0.5) / passes as f32));
+            rgb += channels.to_rgb(channels.emission(white()));
+        }
+        rgb /= passes as f32;
+        assert!((rgb - white()).magnitude() < 0.005, "white came back as {:?}", rgb);
+    }
+
+    #[test]
+    fn dropped_wavelengths_stop_counting() {
+        let values = Vector4::new(0.1, 0.9, 0.5, 0.7);
+        let mut wavelengths = Wavelengths::sample(0.3);
+        assert_eq!(Channels::Spectral(wavelengths).max_live(values), 0.9);
+        wavelengths.terminate_secondary();
+        assert!(wavelengths.is_secondary_terminated());
+        assert_eq!(Channels::Spectral(wavelengths).max_live(values), 0.1);
+        assert_eq!(Channels::Rgb.max_live(Vector4::new(0.2, 0.4, 0.3, 0.0)), 0.4);
+    }
+}
@@ -35,7 +35,7 @@ impl Bounded for Sphere<'_> {
 }
 
 impl Hitable for Sphere<'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = r.origin - self.center;
         let a = dot(r.direction, r.direction);
         let b = dot(oc, r.direction);
@@ -52,9 +52,10 @@ impl Hitable for Sphere<'_> {
                     t: temp,
                     p: hit_point,
                     normal,
-                    material: &self.material,
+                    material: self.material,
                     u,
-                    v
+                    v,
+                    object: 0
                 });
             }
             temp = (-b + discriminant.sqrt()) / a;
@@ -66,9 +67,10 @@ impl Hitable for Sphere<'_> {
                     t: temp,
                     p: hit_point,
                     normal,
-                    material: &self.material,
+                    material: self.material,
                     u,
-                    v
+                    v,
+                    object: 0
                 });
             }
         }
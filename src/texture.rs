@@ -1,7 +1,8 @@
 use cgmath::Vector3;
-use image;
 
 use crate::perlin::Perlin;
+use crate::rng::Pcg32;
+use crate::util;
 
 pub trait Textured {
     fn value(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32>;
@@ -16,19 +17,25 @@ pub enum Texture<'texture> {
 
 impl<'texture> Texture<'texture> {
     pub fn constant(r: f32, g: f32, b: f32) -> Texture<'texture> {
-        Texture::Constant(ConstantTexture { color: Vector3::new(r, g, b) })
+        Texture::Constant(ConstantTexture::new(r, g, b))
     }
 
     pub fn checker(t0: &'texture Texture, t1: &'texture Texture) -> Texture<'texture> {
         Texture::Checker(CheckerTexture::new(t0, t1))
     }
 
-    pub fn noise(scale: f32) -> Texture<'texture> {
-        Texture::Noise(NoiseTexture::new(scale))
+    pub fn noise(scale: f32, rng: &mut Pcg32) -> Texture<'texture> {
+        Texture::Noise(NoiseTexture::new(scale, rng))
     }
 
-    pub fn image(path_str: &str) -> Texture<'texture> {
-        Texture::Image(ImageTexture::new(path_str))
+    pub fn image(path_str: &str, linear: bool) -> Texture<'texture> {
+        Texture::Image(ImageTexture::new(path_str, linear))
+    }
+
+    // Like `image`, but reports a missing or unreadable file instead of
+    // falling back to a placeholder.
+    pub fn load_image(path_str: &str, linear: bool) -> Result<Texture<'texture>, image::ImageError> {
+        ImageTexture::load(path_str, linear).map(Texture::Image)
     }
 }
 
@@ -56,7 +63,7 @@ impl ConstantTexture {
 }
 
 impl Textured for ConstantTexture {
-    fn value(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32> {
+    fn value(&self, _u: f32, _v: f32, _p: &Vector3<f32>) -> Vector3<f32> {
         self.color
     }
 }
@@ -69,8 +76,8 @@ pub struct CheckerTexture<'texture> {
 impl<'texture> CheckerTexture<'texture> {
     pub fn new(even: &'texture Texture, odd: &'texture Texture) -> Self {
         Self {
-            even: even,
-            odd: odd
+            even,
+            odd
         }
     }
 }
@@ -92,62 +99,65 @@ pub struct NoiseTexture {
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f32) -> Self {
+    pub fn new(scale: f32, rng: &mut Pcg32) -> Self {
         Self {
-            noise: Perlin::new(),
+            noise: Perlin::new(rng),
             scale
         }
     }
 }
 
 impl Textured for NoiseTexture {
-    fn value(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32> {
-        Vector3::new(1.0, 1.0, 1.0) * 0.5 * (1.0 + (self.scale * &p.z + 10.0 * self.noise.turb(1.0 * p, 7)).sin())
+    fn value(&self, _u: f32, _v: f32, p: &Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(1.0, 1.0, 1.0) * 0.5 * (1.0 + (self.scale * p.z + 10.0 * self.noise.turb(1.0 * p, 7)).sin())
     }
 }
 
 pub struct ImageTexture {
-    data: Vec<u8>,
+    // linear RGB values
+    data: Vec<f32>,
     nx: i32,
     ny: i32
 }
 
 impl ImageTexture {
-    pub fn new(path_str: &str) -> Self {
-        let path = std::path::Path::new(path_str);
-        if let Ok((width, height)) = image::image_dimensions(path) {
-            if let Ok(img) = image::open(path) {
-                Self {
-                    data: img.raw_pixels(),
-                    nx: width as i32,
-                    ny: height as i32
-                }
-            } else {
-                Self {
-                    data: vec![255, 255, 0],
-                    nx: 1,
-                    ny: 1
-                }
-            }
-        } else {
-            Self {
-                data: vec![255, 255, 0],
-                nx: 1,
-                ny: 1
-            }
-        }
+    // Images hold sRGB encoded colors, which are decoded to linear light on
+    // load. Data that isn't a color, such as roughness or bump maps, is stored
+    // linearly and must be loaded with `linear` set so it's left as it is.
+    // A file that can't be read becomes a single yellow pixel.
+    pub fn new(path_str: &str, linear: bool) -> Self {
+        Self::load(path_str, linear).unwrap_or_else(|_| Self {
+            data: vec![1.0, 1.0, 0.0],
+            nx: 1,
+            ny: 1
+        })
+    }
+
+    pub fn load(path_str: &str, linear: bool) -> Result<Self, image::ImageError> {
+        let decode: Vec<f32> = (0..=255).map(|byte| {
+            let x = byte as f32 / 255.0;
+            if linear { x } else { util::srgb_to_linear(x) }
+        }).collect();
+
+        let img = image::open(std::path::Path::new(path_str))?.to_rgb();
+        let (width, height) = img.dimensions();
+        Ok(Self {
+            data: img.into_raw().iter().map(|&byte| decode[byte as usize]).collect(),
+            nx: width as i32,
+            ny: height as i32
+        })
     }
 }
 
 impl Textured for ImageTexture {
-    fn value(&self, u: f32, v: f32, p: &Vector3<f32>) -> Vector3<f32> {
+    fn value(&self, u: f32, v: f32, _p: &Vector3<f32>) -> Vector3<f32> {
         let mut i = (u * self.nx as f32) as i32;
         let mut j = ((1.0 - v) * self.ny as f32 - 0.001) as i32; // TODO: eps?
         i = i.max(0).min(self.nx - 1);
         j = j.max(0).min(self.ny - 1);
-        let r = self.data[(3 * i + 3 * self.nx * j    ) as usize] as f32 / 255.0;
-        let g = self.data[(3 * i + 3 * self.nx * j + 1) as usize] as f32 / 255.0;
-        let b = self.data[(3 * i + 3 * self.nx * j + 2) as usize] as f32 / 255.0;
+        let r = self.data[(3 * i + 3 * self.nx * j    ) as usize];
+        let g = self.data[(3 * i + 3 * self.nx * j + 1) as usize];
+        let b = self.data[(3 * i + 3 * self.nx * j + 2) as usize];
         Vector3::new(r, g, b)
     }
 }
\ No newline at end of file
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::Path;
+use std::time::Duration;
+
+// A rectangle of the image that is rendered as one unit of work.
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+// The order tiles are handed out in, which is also the order they appear in
+// the preview.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    // rows of tiles from the top left
+    Scanline,
+    // outwards from the center, which is usually where the subject is
+    Spiral,
+    // along a Hilbert curve, keeping consecutive tiles next to each other
+    Hilbert
+}
+
+impl std::str::FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order '{}', expected 'scanline', 'spiral' or 'hilbert'", s))
+        }
+    }
+}
+
+// Cuts the image into tiles of at most `size` pixels square, in the given order.
+pub fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+
+    let mut grid: Vec<(usize, usize)> = Vec::with_capacity(columns * rows);
+    match order {
+        TileOrder::Scanline => {
+            for row in 0..rows {
+                grid.extend((0..columns).map(|column| (column, row)));
+            }
+        },
+        TileOrder::Spiral => spiral(columns, rows, &mut grid),
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            for row in 0..rows {
+                grid.extend((0..columns).map(|column| (column, row)));
+            }
+            grid.sort_by_key(|&(column, row)| hilbert_index(n, column, row));
+        }
+    }
+
+    grid.into_iter().map(|(column, row)| {
+        let x = column * size;
+        let y = row * size;
+        Tile {
+            x,
+            y,
+            width: size.min(width - x),
+            height: size.min(height - y)
+        }
+    }).collect()
+}
+
+// Walks a square spiral out from the middle of the grid, keeping the cells that
+// fall inside it, until every cell has been visited.
+fn spiral(columns: usize, rows: usize, grid: &mut Vec<(usize, usize)>) {
+    let total = columns * rows;
+    let (mut x, mut y) = ((columns / 2) as isize, (rows / 2) as isize);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut step = 1;
+    let mut direction = 0;
+
+    let visit = |x: isize, y: isize, grid: &mut Vec<(usize, usize)>| {
+        if x >= 0 && y >= 0 && (x as usize) < columns && (y as usize) < rows {
+            grid.push((x as usize, y as usize));
+        }
+    };
+
+    visit(x, y, grid);
+    while grid.len() < total {
+        // each step length is walked twice, turning after each
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction];
+            for _ in 0..step {
+                x += dx;
+                y += dy;
+                visit(x, y, grid);
+            }
+            direction = (direction + 1) % 4;
+        }
+        step += 1;
+    }
+}
+
+// Position of a cell along the Hilbert curve that fills an n by n grid, with n
+// a power of two.
+fn hilbert_index(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        // rotate the quadrant so the curve inside it has the right orientation
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+// Draws the border of a tile into an ARGB buffer.
+pub fn outline(buffer: &mut [u32], width: usize, tile: &Tile, color: u32) {
+    for x in tile.x..tile.x + tile.width {
+        buffer[tile.y * width + x] = color;
+        buffer[(tile.y + tile.height - 1) * width + x] = color;
+    }
+    for y in tile.y..tile.y + tile.height {
+        buffer[y * width + tile.x] = color;
+        buffer[y * width + tile.x + tile.width - 1] = color;
+    }
+}
+
+// Time spent on each tile over the whole render, in the order they were handed out.
+pub struct TileStats {
+    pub tiles: Vec<Tile>,
+    pub times: Vec<Duration>
+}
+
+impl TileStats {
+    // Writes every tile's position, size and time as CSV, one tile per line.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "x,y,width,height,ms")?;
+        for (tile, time) in self.tiles.iter().zip(&self.times) {
+            writeln!(out, "{},{},{},{},{:.3}", tile.x, tile.y, tile.width, tile.height, time.as_secs_f64() * 1000.0)?;
+        }
+        out.flush()
+    }
+}
+
+impl fmt::Display for TileStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = self.times.len();
+        if count == 0 {
+            return write!(f, "no tiles");
+        }
+
+        let total: Duration = self.times.iter().sum();
+        let (fastest, slowest) = (0..count).fold((0, 0), |(min, max), i| (
+            if self.times[i] < self.times[min] { i } else { min },
+            if self.times[i] > self.times[max] { i } else { max }
+        ));
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} tiles, {:.1} ms on average, fastest {:.1} ms at ({}, {}), slowest {:.1} ms at ({}, {})",
+            count,
+            millis(total) / count as f64,
+            millis(self.times[fastest]),
+            self.tiles[fastest].x,
+            self.tiles[fastest].y,
+            millis(self.times[slowest]),
+            self.tiles[slowest].x,
+            self.tiles[slowest].y
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // image sizes that do and don't divide evenly into tiles of the given size
+    const SIZES: [(usize, usize, usize); 6] = [(64, 64, 16), (100, 37, 16), (1, 1, 32), (17, 300, 8), (33, 5, 64), (45, 45, 7)];
+
+    // Checks that the tiles cover every pixel of the image exactly once.
+    fn check_coverage(order: TileOrder) {
+        for &(width, height, size) in &SIZES {
+            let mut covered = vec![0; width * height];
+            for tile in tiles(width, height, size, order) {
+                assert!(tile.width > 0 && tile.height > 0 && tile.width <= size && tile.height <= size);
+                for y in tile.y..tile.y + tile.height {
+                    for x in tile.x..tile.x + tile.width {
+                        covered[y * width + x] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&n| n == 1), "{:?} order doesn't cover {}x{} once with {} pixel tiles", order, width, height, size);
+        }
+    }
+
+    #[test]
+    fn scanline_covers_every_pixel_once() {
+        check_coverage(TileOrder::Scanline);
+    }
+
+    #[test]
+    fn spiral_covers_every_pixel_once() {
+        check_coverage(TileOrder::Spiral);
+        let first = tiles(100, 37, 16, TileOrder::Spiral)[0];
+        assert_eq!((first.x, first.y), (48, 16));
+    }
+
+    #[test]
+    fn hilbert_covers_every_pixel_once() {
+        check_coverage(TileOrder::Hilbert);
+        // on a power of two grid every tile is next to the one before it
+        let order = tiles(64, 64, 8, TileOrder::Hilbert);
+        for pair in order.windows(2) {
+            let step = (pair[0].x as isize - pair[1].x as isize).abs() + (pair[0].y as isize - pair[1].y as isize).abs();
+            assert_eq!(step, 8);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use cgmath::Vector3;
+
+use crate::util;
+
+// How radiance beyond what a display can show is brought into range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    // cut off every channel at 1, which shifts the hue of bright colors
+    Clamp,
+    // L / (1 + L) on the luminance, so bright colors keep their hue
+    Reinhard,
+    // Reinhard that reaches white at a given luminance instead of at infinity
+    ExtendedReinhard,
+    // John Hable's filmic curve from Uncharted 2
+    Hable,
+    // Krzysztof Narkowicz's fit of the ACES filmic curve
+    Aces
+}
+
+impl Operator {
+    // Luminance that maps to white, for the operators that have one.
+    pub fn default_white(self) -> f32 {
+        match self {
+            Operator::Hable => 5.6,
+            _ => 4.0
+        }
+    }
+}
+
+impl std::str::FromStr for Operator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(Operator::Clamp),
+            "reinhard" => Ok(Operator::Reinhard),
+            "extended-reinhard" => Ok(Operator::ExtendedReinhard),
+            "hable" => Ok(Operator::Hable),
+            "aces" => Ok(Operator::Aces),
+            _ => Err(format!("unknown tone mapping operator '{}', expected 'clamp', 'reinhard', 'extended-reinhard', 'hable' or 'aces'", s))
+        }
+    }
+}
+
+// Checks a white point given by the user, since the curves fold back on
+// themselves below 0.
+pub fn white_point(white: f32) -> Result<f32, String> {
+    if white > 0.0 {
+        Ok(white)
+    } else {
+        Err(String::from("white must be greater than 0"))
+    }
+}
+
+// Maps linear radiance to linear display values between 0 and 1.
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMap {
+    pub operator: Operator,
+    // in stops, each one doubling the brightness
+    pub exposure: f32,
+    // luminance that maps to white for extended Reinhard and Hable, None for
+    // the operator's default
+    pub white: Option<f32>
+}
+
+impl Default for ToneMap {
+    // what the renderer always did: no exposure change and a hard clamp
+    fn default() -> Self {
+        Self::new(Operator::Clamp)
+    }
+}
+
+impl ToneMap {
+    pub fn new(operator: Operator) -> Self {
+        Self {
+            operator,
+            exposure: 0.0,
+            white: None
+        }
+    }
+
+    fn white(&self) -> f32 {
+        self.white.unwrap_or_else(|| self.operator.default_white())
+    }
+
+    pub fn apply(&self, color: Vector3<f32>) -> Vector3<f32> {
+        let c = color * 2.0f32.powf(self.exposure);
+        let mapped = match self.operator {
+            Operator::Clamp => c,
+            Operator::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            Operator::ExtendedReinhard => {
+                let white2 = self.white() * self.white();
+                scale_luminance(c, |l| l * (1.0 + l / white2) / (1.0 + l))
+            },
+            Operator::Hable => {
+                // the curve is usually fed twice the exposure, then scaled so
+                // that the white point ends up at 1
+                let scale = 1.0 / hable(2.0 * self.white());
+                c.map(|x| hable(2.0 * x) * scale)
+            },
+            Operator::Aces => c.map(|x| {
+                let x = 0.6 * x;
+                x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)
+            })
+        };
+        mapped.map(|x| x.clamp(0.0, 1.0))
+    }
+}
+
+fn scale_luminance<F: Fn(f32) -> f32>(c: Vector3<f32>, curve: F) -> Vector3<f32> {
+    let luminance = util::luminance(c);
+    if luminance <= 0.0 {
+        return c;
+    }
+    c * (curve(luminance) / luminance)
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    const OPERATORS: [Operator; 5] = [Operator::Clamp, Operator::Reinhard, Operator::ExtendedReinhard, Operator::Hable, Operator::Aces];
+
+    fn grey(x: f32) -> Vector3<f32> {
+        Vector3::new(x, x, x)
+    }
+
+    #[test]
+    fn maps_the_white_point_to_white() {
+        for &operator in &[Operator::ExtendedReinhard, Operator::Hable] {
+            for &white in &[None, Some(1.0), Some(2.5), Some(16.0)] {
+                let tone_map = ToneMap { white, ..ToneMap::new(operator) };
+                let mapped = tone_map.apply(grey(tone_map.white()));
+                assert!((mapped - grey(1.0)).magnitude() < 1e-5, "{:?} maps white to {:?}", operator, mapped);
+                // and doesn't just reach it early and clamp
+                assert!(tone_map.apply(grey(0.99 * tone_map.white())).x < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn brighter_never_maps_darker() {
+        let tints = [grey(1.0), Vector3::new(1.0, 0.2, 0.05), Vector3::new(0.1, 0.3, 2.0)];
+        for &operator in &OPERATORS {
+            for &exposure in &[-2.0, 0.0, 3.0] {
+                let tone_map = ToneMap { exposure, ..ToneMap::new(operator) };
+                for tint in &tints {
+                    let mut previous = tone_map.apply(tint * 0.0);
+                    for i in 1..=400 {
+                        let mapped = tone_map.apply(tint * (i as f32 * 0.05));
+                        assert!(mapped.x >= previous.x && mapped.y >= previous.y && mapped.z >= previous.z,
+                                "{:?} went from {:?} to {:?}", operator, previous, mapped);
+                        previous = mapped;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_white_points_that_are_not_positive() {
+        assert_eq!(white_point(0.5), Ok(0.5));
+        for &white in &[0.0, -0.0, -4.0, f32::NAN] {
+            assert_eq!(white_point(white), Err(String::from("white must be greater than 0")));
+        }
+    }
+}
@@ -1,35 +1,78 @@
 use cgmath::{
     Vector3,
-    InnerSpace,
-    dot
+    InnerSpace
 };
 
-use rand::prelude::*;
 use std::f32::consts;
 
 const TWO_PI: f32 = 2.0 * consts::PI;
 
-pub fn random_in_unit_sphere() -> Vector3<f32> {
-    let mut p = Vector3::new(1.0, 1.0, 1.0);
-    let unit = Vector3::new(1.0, 1.0, 1.0);
-    while p.magnitude2() >= 1.0 {
-        p.x = random::<f32>();
-        p.y = random::<f32>();
-        p.z = random::<f32>();
-        p = 2.0 * p - unit;
+// The functions below turn a 2D sample, uniform over the unit square, into a
+// point or direction with some distribution. Nearby samples map to nearby
+// results, so well spread samples stay well spread.
+
+// A uniformly distributed direction.
+pub fn sample_unit_vector(u: (f32, f32)) -> Vector3<f32> {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = TWO_PI * u.1;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// A direction in the hemisphere around the z axis with density cos(theta) / pi,
+// found by picking a point on the unit disk and projecting it up onto the
+// hemisphere. Used with an `Onb` to sample Lambertian reflection.
+pub fn sample_cosine_direction(u: (f32, f32)) -> Vector3<f32> {
+    let d = sample_unit_disk(u);
+    Vector3::new(d.x, d.y, (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt())
+}
+
+// A direction around the z axis with density proportional to cos^exponent of
+// its angle to the axis, the lobe of a Phong reflection.
+pub fn sample_phong_direction(exponent: f32, u: (f32, f32)) -> Vector3<f32> {
+    let cos_theta = u.0.powf(1.0 / (exponent + 1.0));
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = TWO_PI * u.1;
+    Vector3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+// A uniformly distributed point on the unit disk in the xy plane, using Shirley
+// and Chiu's concentric mapping of squares to circles.
+pub fn sample_unit_disk(u: (f32, f32)) -> Vector3<f32> {
+    let a = 2.0 * u.0 - 1.0;
+    let b = 2.0 * u.1 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
     }
-    p
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, consts::FRAC_PI_2 - consts::FRAC_PI_4 * (a / b))
+    };
+    Vector3::new(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
-pub fn random_in_unit_disk() -> Vector3<f32> {
-    let mut p = Vector3::new(1.0, 1.0, 0.0);
-    let unit = Vector3::new(1.0, 1.0, 0.0);
-    while dot(p, p) >= 1.0 {
-        p.x = random::<f32>();
-        p.y = random::<f32>();
-        p = 2.0 * p - unit;
+// Relative brightness of a linear Rec. 709 color.
+pub fn luminance(c: Vector3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// The sRGB transfer function, between the values stored in 8-bit images and
+// linear light. sRGB shares its primaries with Rec. 709.
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
     }
-    p
 }
 
 pub fn get_sphere_uv(p: Vector3<f32>) -> (f32, f32) {
@@ -37,3 +80,79 @@ pub fn get_sphere_uv(p: Vector3<f32>) -> (f32, f32) {
     let theta = p.y.asin();
     (1.0 - (phi + consts::PI) / TWO_PI, (theta + consts::FRAC_PI_2) / consts::PI)
 }
+
+// An orthonormal basis with `w` along a given direction, for turning
+// directions sampled around the z axis into world space.
+pub struct Onb {
+    pub u: Vector3<f32>,
+    pub v: Vector3<f32>,
+    pub w: Vector3<f32>
+}
+
+impl Onb {
+    pub fn from_w(n: Vector3<f32>) -> Self {
+        let w = n.normalize();
+        let a = if w.x.abs() > 0.9 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(1.0, 0.0, 0.0) };
+        let v = w.cross(a).normalize();
+        let u = w.cross(v);
+        Self { u, v, w }
+    }
+
+    pub fn local(&self, a: Vector3<f32>) -> Vector3<f32> {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+
+    use rand::Rng;
+
+    #[test]
+    fn cosine_directions_stay_in_the_hemisphere() {
+        let mut rng = Pcg32::new(9, 0);
+        let samples = 100_000;
+        // with density cos(theta) / pi, cos(theta) falls below c with chance c^2
+        let limits = [0.25, 0.5, 0.75, 0.9];
+        let mut below = [0; 4];
+        for _ in 0..samples {
+            let d = sample_cosine_direction((rng.gen(), rng.gen()));
+            assert!(d.z >= 0.0, "{:?} is below the horizon", d);
+            assert!((d.magnitude() - 1.0).abs() < 1e-5);
+            for (count, &c) in below.iter_mut().zip(&limits) {
+                if d.z < c {
+                    *count += 1;
+                }
+            }
+        }
+        for (&count, &c) in below.iter().zip(&limits) {
+            let fraction = count as f32 / samples as f32;
+            assert!((fraction - c * c).abs() < 0.005, "cos below {} for {} of the samples", c, fraction);
+        }
+    }
+
+    #[test]
+    fn srgb_round_trips_every_8_bit_value() {
+        for i in 0..=255u32 {
+            let x = i as f32 / 255.0;
+            let back = linear_to_srgb(srgb_to_linear(x));
+            assert!((back - x).abs() < 1e-5, "{} came back as {}", x, back);
+            assert_eq!((255.0 * back + 0.5) as u32, i);
+        }
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-6);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn srgb_thresholds_use_the_linear_segment() {
+        assert_eq!(srgb_to_linear(0.04045), 0.04045 / 12.92);
+        assert_eq!(linear_to_srgb(0.003_130_8), 12.92 * 0.003_130_8);
+        // and the curve picks up where the line leaves off
+        assert!((srgb_to_linear(0.040_451) - 0.04045 / 12.92).abs() < 1e-6);
+        assert!((linear_to_srgb(0.003_130_9) - 12.92 * 0.003_130_8).abs() < 1e-5);
+    }
+}
@@ -2,9 +2,9 @@ use cgmath::Vector3;
 
 use crate::hitable::Geometry;
 use crate::material::Material;
-use crate::texture::Texture;
 
 
+#[allow(clippy::too_many_arguments, clippy::vec_init_then_push)]
 pub fn cornell_box<'material>(
     green_material: &'material Material,
     red_material: &'material Material,